@@ -0,0 +1,217 @@
+//! Project configuration files
+//!
+//! Style options can be set in a `ponyfmt.toml` (or `.ponyfmt.toml`) file.
+//! The configuration for a source file is found by walking up from its
+//! directory: every config file on the way applies, with files closer to the
+//! source overriding the ones further up. A config file containing
+//! `root = true` stops the search, so a vendored package can carry its own
+//! settings without inheriting those of the enclosing project.
+//!
+//! ```toml
+//! root = true
+//! indent_width = 2
+//! max_width = 100
+//! max_blank_lines = 1
+//! sort_uses = false
+//! wrap_docstrings = false
+//! ```
+
+use crate::error::FormatError;
+use crate::formatter::FormatOptions;
+use serde::Deserialize;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+/// Names of the configuration files, in the order they are looked up in a directory
+pub const CONFIG_FILE_NAMES: [&str; 2] = ["ponyfmt.toml", ".ponyfmt.toml"];
+
+/// Style options read from a config file; unset options are inherited
+#[derive(Clone, Debug, Default, Deserialize, PartialEq, Eq)]
+#[serde(deny_unknown_fields)]
+pub struct Config {
+    /// Do not look for config files in parent directories
+    #[serde(default)]
+    pub root: bool,
+    /// Number of spaces per indentation level
+    pub indent_width: Option<usize>,
+    /// Maximum line width
+    pub max_width: Option<usize>,
+    /// Maximum number of consecutive blank lines kept between members and statements
+    pub max_blank_lines: Option<usize>,
+    /// Sort consecutive `use` statements alphabetically
+    pub sort_uses: Option<bool>,
+    /// Re-wrap docstring paragraphs to the maximum line width
+    pub wrap_docstrings: Option<bool>,
+}
+
+impl Config {
+    /// Read a config file
+    pub fn from_file(path: &Path) -> Result<Self, FormatError> {
+        let content = fs::read_to_string(path).map_err(|e| {
+            config_error(format!("failed to read config file: {}", e)).with_path(path)
+        })?;
+        let config: Self = toml::from_str(&content)
+            .map_err(|e| config_error(format!("invalid config file: {}", e)).with_path(path))?;
+        config.validate().map_err(|e| e.with_path(path))?;
+        Ok(config)
+    }
+
+    /// Check that the options set have usable values
+    pub fn validate(&self) -> Result<(), FormatError> {
+        if self.indent_width == Some(0) {
+            return Err(config_error("indent_width must be at least 1"));
+        }
+        if self.max_width == Some(0) {
+            return Err(config_error("max_width must be at least 1"));
+        }
+        Ok(())
+    }
+
+    /// Override the options of `self` with those set in `other`
+    pub fn merge(&mut self, other: Config) {
+        self.root |= other.root;
+        self.indent_width = other.indent_width.or(self.indent_width);
+        self.max_width = other.max_width.or(self.max_width);
+        self.max_blank_lines = other.max_blank_lines.or(self.max_blank_lines);
+        self.sort_uses = other.sort_uses.or(self.sort_uses);
+        self.wrap_docstrings = other.wrap_docstrings.or(self.wrap_docstrings);
+    }
+
+    /// Set the options of `opts` that this config specifies
+    pub fn apply(&self, opts: &mut FormatOptions) {
+        if let Some(indent_width) = self.indent_width {
+            opts.indent_width = indent_width;
+        }
+        if let Some(max_width) = self.max_width {
+            opts.max_width = max_width;
+        }
+        if let Some(max_blank_lines) = self.max_blank_lines {
+            opts.max_blank_lines = max_blank_lines;
+        }
+        if let Some(sort_uses) = self.sort_uses {
+            opts.sort_uses = sort_uses;
+        }
+        if let Some(wrap_docstrings) = self.wrap_docstrings {
+            opts.wrap_docstrings = wrap_docstrings;
+        }
+    }
+}
+
+/// The configuration that applies to a path
+#[derive(Debug, Default)]
+pub struct ResolvedConfig {
+    /// Options merged from all config files that apply
+    pub config: Config,
+    /// The config files that were merged, outermost first
+    pub sources: Vec<PathBuf>,
+}
+
+/// Find and merge the config files that apply to `path` (a file or directory)
+pub fn resolve(path: &Path) -> Result<ResolvedConfig, FormatError> {
+    let path = std::path::absolute(path)
+        .map_err(|e| config_error(format!("failed to resolve {}: {}", path.display(), e)))?;
+    let start = if path.is_dir() {
+        path.as_path()
+    } else {
+        path.parent().unwrap_or(&path)
+    };
+
+    // Collect from the innermost directory outwards, stopping at a root config
+    let mut found = Vec::new();
+    for dir in start.ancestors() {
+        let Some(file) = CONFIG_FILE_NAMES
+            .iter()
+            .map(|name| dir.join(name))
+            .find(|file| file.is_file())
+        else {
+            continue;
+        };
+        let config = Config::from_file(&file)?;
+        let is_root = config.root;
+        found.push((file, config));
+        if is_root {
+            break;
+        }
+    }
+
+    let mut resolved = ResolvedConfig::default();
+    for (file, config) in found.into_iter().rev() {
+        resolved.config.merge(config);
+        resolved.sources.push(file);
+    }
+    Ok(resolved)
+}
+
+fn config_error(message: impl Into<String>) -> FormatError {
+    FormatError::Config {
+        path: None,
+        message: message.into(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir =
+            std::env::temp_dir().join(format!("ponyfmt-config-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(dir.join("vendor/pkg")).unwrap();
+        dir
+    }
+
+    #[test]
+    fn nested_config_overrides_parent() {
+        let dir = temp_dir("nested");
+        fs::write(
+            dir.join("ponyfmt.toml"),
+            "indent_width = 4\nmax_width = 80\n",
+        )
+        .unwrap();
+        fs::write(dir.join("vendor/.ponyfmt.toml"), "max_width = 120\n").unwrap();
+
+        let resolved = resolve(&dir.join("vendor/pkg/main.pony")).unwrap();
+        assert_eq!(resolved.config.indent_width, Some(4));
+        assert_eq!(resolved.config.max_width, Some(120));
+        assert_eq!(resolved.sources.len(), 2);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn root_config_stops_discovery() {
+        let dir = temp_dir("root");
+        fs::write(dir.join("ponyfmt.toml"), "indent_width = 4\n").unwrap();
+        fs::write(
+            dir.join("vendor/ponyfmt.toml"),
+            "root = true\nsort_uses = true\n",
+        )
+        .unwrap();
+
+        let resolved = resolve(&dir.join("vendor/pkg")).unwrap();
+        assert_eq!(resolved.config.indent_width, None);
+        assert_eq!(resolved.config.sort_uses, Some(true));
+        assert_eq!(resolved.sources, vec![dir.join("vendor/ponyfmt.toml")]);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn zero_widths_are_rejected() {
+        let dir = temp_dir("zero");
+        fs::write(dir.join("ponyfmt.toml"), "indent_width = 0\n").unwrap();
+        let err = resolve(&dir).unwrap_err();
+        assert_eq!(err.path(), Some(dir.join("ponyfmt.toml").as_path()));
+        assert!(err.to_string().contains("indent_width must be at least 1"));
+        fs::remove_dir_all(&dir).unwrap();
+
+        let config: Config = toml::from_str("max_width = 0\n").unwrap();
+        assert!(config.validate().is_err());
+    }
+
+    #[test]
+    fn unknown_options_are_rejected() {
+        assert!(toml::from_str::<Config>("indent = 4\n").is_err());
+    }
+}
@@ -0,0 +1,194 @@
+//! Syntax error diagnostics
+//!
+//! tree-sitter recovers from syntax errors by wrapping the tokens it could
+//! not place in ERROR nodes and inserting zero-width MISSING nodes for tokens
+//! it expected. [`syntax_errors`](crate::diagnostics::syntax_errors) turns
+//! each of them into a [`Diagnostic`](crate::diagnostics::Diagnostic) that
+//! renders like a rustc error, with the offending source line and a caret
+//! underline:
+//!
+//! ```text
+//! error: unexpected `,`
+//!  --> src/main.pony:3:10
+//!   |
+//! 3 |     foo(1,, 2)
+//!   |          ^ unexpected token
+//! ```
+
+use crate::error::Span;
+use std::fmt::Write;
+use std::path::Path;
+use tree_sitter::Node;
+
+/// A syntax error found in the parsed source
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Diagnostic {
+    /// What went wrong, e.g. ``unexpected `then` `` or ``missing `end` ``
+    pub message: String,
+    /// Short note printed next to the caret underline
+    pub label: String,
+    /// Where the error is; MISSING nodes have a zero-width span
+    pub span: Span,
+    /// Text of the source line the span starts on
+    pub source_line: String,
+}
+
+impl Diagnostic {
+    /// Render the diagnostic rustc-style, naming `path` in the location line
+    pub fn render(&self, path: Option<&Path>) -> String {
+        let line_number = self.span.line.to_string();
+        let gutter = " ".repeat(line_number.len());
+        let location = match path {
+            Some(path) => format!("{}:{}:{}", path.display(), self.span.line, self.span.column),
+            None => format!("{}:{}", self.span.line, self.span.column),
+        };
+
+        // Underline up to the end of the first line of the span, at least one column
+        let line_columns = self.source_line.chars().count() + 1;
+        let end_column = if self.span.end_line == self.span.line {
+            self.span.end_column
+        } else {
+            line_columns
+        };
+        let width = end_column.saturating_sub(self.span.column).max(1);
+        let padding: String = self
+            .source_line
+            .chars()
+            .take(self.span.column - 1)
+            .map(|c| if c == '\t' { '\t' } else { ' ' })
+            .collect();
+
+        let mut out = String::new();
+        let _ = writeln!(out, "error: {}", self.message);
+        let _ = writeln!(out, "{}--> {}", gutter, location);
+        let _ = writeln!(out, "{} |", gutter);
+        let _ = writeln!(out, "{} | {}", line_number, self.source_line);
+        let _ = write!(
+            out,
+            "{} | {}{} {}",
+            gutter,
+            padding,
+            "^".repeat(width),
+            self.label
+        );
+        out
+    }
+}
+
+/// Every ERROR and MISSING node below `root`, in source order
+pub fn syntax_errors(root: Node, source: &str) -> Vec<Diagnostic> {
+    let mut out = Vec::new();
+    collect(root, source, &mut out);
+    out
+}
+
+fn collect(node: Node, source: &str, out: &mut Vec<Diagnostic>) {
+    if node.is_missing() {
+        let expected = if node.is_named() {
+            node.kind().replace('_', " ")
+        } else {
+            format!("`{}`", node.kind())
+        };
+        out.push(Diagnostic {
+            message: format!("missing {}", expected),
+            label: format!("expected {}", expected),
+            span: Span::of(node, source.as_bytes()),
+            source_line: source_line(source, node.start_byte()),
+        });
+        return;
+    }
+    if node.is_error() {
+        // Point at the first token tree-sitter could not place
+        let token = first_token(node);
+        let text = &source[token.start_byte()..token.end_byte()];
+        let text = text.lines().next().unwrap_or_default();
+        out.push(Diagnostic {
+            message: format!("unexpected `{}`", text),
+            label: "unexpected token".to_string(),
+            span: Span::of(token, source.as_bytes()),
+            source_line: source_line(source, token.start_byte()),
+        });
+        return;
+    }
+    if !node.has_error() {
+        return;
+    }
+    for child in node.children(&mut node.walk()) {
+        collect(child, source, out);
+    }
+}
+
+/// First leaf below `node` that is not a comment
+fn first_token(node: Node) -> Node {
+    let mut cursor = node.walk();
+    let first = node
+        .children(&mut cursor)
+        .find(|child| !matches!(child.kind(), "line_comment" | "block_comment"))
+        .map(first_token);
+    first.unwrap_or(node)
+}
+
+/// The line of `source` containing byte `offset`, without its line break
+fn source_line(source: &str, offset: usize) -> String {
+    let start = source[..offset].rfind('\n').map_or(0, |i| i + 1);
+    let end = source[offset..]
+        .find('\n')
+        .map_or(source.len(), |i| offset + i);
+    source[start..end].trim_end_matches('\r').to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parser::parse;
+
+    fn render(source: &str) -> Vec<String> {
+        let tree = parse(source).unwrap();
+        syntax_errors(tree.root_node(), source)
+            .iter()
+            .map(|d| d.render(Some(Path::new("main.pony"))))
+            .collect()
+    }
+
+    #[test]
+    fn unexpected_tokens_are_underlined() {
+        assert_eq!(
+            render("actor Main\n  fun f() =>\n    foo(1,, 2)\n"),
+            vec![
+                "error: unexpected `,`\n \
+                 --> main.pony:3:10\n  \
+                 |\n\
+                 3 |     foo(1,, 2)\n  \
+                 |          ^ unexpected token"
+            ]
+        );
+    }
+
+    #[test]
+    fn columns_are_counted_in_characters() {
+        assert_eq!(
+            render("actor Main\n  fun f() =>\n    g(\"éé\", 1,, 2)\n"),
+            vec![
+                "error: unexpected `,`\n \
+                 --> main.pony:3:15\n  \
+                 |\n\
+                 3 |     g(\"éé\", 1,, 2)\n  \
+                 |               ^ unexpected token"
+            ]
+        );
+    }
+
+    #[test]
+    fn missing_tokens_name_what_was_expected() {
+        assert_eq!(
+            render("actor Main\n  fun f(): U32 =>\n    let x = 1 +\n"),
+            vec![
+                "error: missing identifier\n \
+                 --> main.pony:3:16\n  \
+                 |\n\
+                 3 |     let x = 1 +\n  \
+                 |                ^ expected identifier"
+            ]
+        );
+    }
+}
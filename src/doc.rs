@@ -0,0 +1,284 @@
+//! Document IR and layout engine
+//!
+//! The formatter does not write strings directly. Instead it builds a [`Doc`]
+//! tree describing the *possible* layouts of the output (in the style of
+//! Wadler's "prettier printer" and Prettier's document builders), and
+//! [`print`] then picks, for every [`Doc::Group`], whether it can be laid out
+//! flat on the current line or has to be broken against the configured
+//! maximum line width.
+//!
+//! Indentation is emitted lazily: a line break only records that the next
+//! piece of text starts a new line, and that text is indented according to
+//! the [`Doc::Indent`] nesting it appears in. This keeps blank lines free of
+//! trailing whitespace and lets callers open an indented region either before
+//! or after the line break that precedes it.
+
+/// A document describing one or more possible layouts of formatted output
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Doc {
+    /// Literal text. May contain newlines (e.g. multi-line string literals),
+    /// in which case it is written verbatim and never fits in flat mode.
+    Text(String),
+    /// A space when the enclosing group is flat, a line break otherwise
+    Line,
+    /// Nothing when the enclosing group is flat, a line break otherwise
+    SoftLine,
+    /// A line break regardless of the enclosing group; forces it to break
+    HardLine,
+    /// Text deferred to the end of the current output line, used for trailing
+    /// comments; forces the enclosing group to break
+    LineSuffix(String),
+    /// The first document when the enclosing group is broken, the second
+    /// when it is flat
+    IfBreak(Box<Doc>, Box<Doc>),
+    /// Increase the indentation of everything inside by one level
+    Indent(Box<Doc>),
+    /// A unit that is either printed entirely flat or with its lines broken
+    Group(Box<Doc>),
+    /// A sequence of documents printed one after another
+    Concat(Vec<Doc>),
+}
+
+impl Doc {
+    pub fn text(text: impl Into<String>) -> Self {
+        Doc::Text(text.into())
+    }
+
+    pub fn indent(doc: Doc) -> Self {
+        Doc::Indent(Box::new(doc))
+    }
+
+    pub fn group(doc: Doc) -> Self {
+        Doc::Group(Box::new(doc))
+    }
+
+    pub fn if_break(broken: Doc, flat: Doc) -> Self {
+        Doc::IfBreak(Box::new(broken), Box::new(flat))
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum LayoutMode {
+    Flat,
+    Break,
+}
+
+/// Render a document, breaking groups that do not fit within `max_width` columns
+pub fn print(doc: &Doc, max_width: usize, indent_width: usize) -> String {
+    let mut output = String::new();
+    let mut column = 0;
+    let mut pending_newline = false;
+    let mut line_suffix = String::new();
+    let mut stack: Vec<(usize, LayoutMode, &Doc)> = vec![(0, LayoutMode::Break, doc)];
+
+    while let Some((indent, mode, doc)) = stack.pop() {
+        match doc {
+            Doc::Text(text) => {
+                if text.is_empty() {
+                    continue;
+                }
+                if pending_newline {
+                    column = indent * indent_width;
+                    output.push_str(&" ".repeat(column));
+                    pending_newline = false;
+                }
+                output.push_str(text);
+                // Columns are counted in characters, not bytes
+                match text.rfind('\n') {
+                    Some(pos) => column = text[pos + 1..].chars().count(),
+                    None => column += text.chars().count(),
+                }
+            }
+            Doc::Line | Doc::SoftLine if mode == LayoutMode::Flat => {
+                if matches!(doc, Doc::Line) && !pending_newline {
+                    output.push(' ');
+                    column += 1;
+                }
+            }
+            Doc::Line | Doc::SoftLine | Doc::HardLine => {
+                output.push_str(&line_suffix);
+                line_suffix.clear();
+                output.push('\n');
+                column = 0;
+                pending_newline = true;
+            }
+            Doc::LineSuffix(text) => line_suffix.push_str(text),
+            Doc::IfBreak(broken, flat) => match mode {
+                LayoutMode::Break => stack.push((indent, mode, broken)),
+                LayoutMode::Flat => stack.push((indent, mode, flat)),
+            },
+            Doc::Indent(inner) => stack.push((indent + 1, mode, inner)),
+            Doc::Group(inner) => {
+                let start = if pending_newline {
+                    indent * indent_width
+                } else {
+                    column
+                };
+                let next_mode = if mode == LayoutMode::Flat
+                    || fits(inner, &stack, max_width.saturating_sub(start))
+                {
+                    LayoutMode::Flat
+                } else {
+                    LayoutMode::Break
+                };
+                stack.push((indent, next_mode, inner));
+            }
+            Doc::Concat(docs) => {
+                for child in docs.iter().rev() {
+                    stack.push((indent, mode, child));
+                }
+            }
+        }
+    }
+
+    output.push_str(&line_suffix);
+    output
+}
+
+/// Check whether `doc` laid out flat, followed by the rest of the current
+/// line, fits into `width` columns.
+fn fits(doc: &Doc, rest: &[(usize, LayoutMode, &Doc)], width: usize) -> bool {
+    let mut remaining = width as isize;
+    let mut rest_index = rest.len();
+    let mut stack: Vec<(LayoutMode, &Doc)> = vec![(LayoutMode::Flat, doc)];
+
+    loop {
+        let (mode, doc) = match stack.pop() {
+            Some(entry) => entry,
+            None => {
+                // The group itself fits; keep measuring what follows it on
+                // the same line, in the mode it was already assigned.
+                if rest_index == 0 {
+                    return true;
+                }
+                rest_index -= 1;
+                let (_, mode, doc) = rest[rest_index];
+                (mode, doc)
+            }
+        };
+
+        match doc {
+            Doc::Text(text) => match text.split_once('\n') {
+                Some((first, _)) => {
+                    return mode == LayoutMode::Break
+                        && remaining >= first.chars().count() as isize;
+                }
+                None => remaining -= text.chars().count() as isize,
+            },
+            Doc::Line if mode == LayoutMode::Flat => remaining -= 1,
+            Doc::SoftLine if mode == LayoutMode::Flat => {}
+            Doc::HardLine if mode == LayoutMode::Flat => return false,
+            Doc::Line | Doc::SoftLine | Doc::HardLine => return true,
+            Doc::LineSuffix(_) if mode == LayoutMode::Flat => return false,
+            Doc::LineSuffix(_) => {}
+            Doc::IfBreak(broken, flat) => match mode {
+                LayoutMode::Break => stack.push((mode, broken)),
+                LayoutMode::Flat => stack.push((mode, flat)),
+            },
+            Doc::Indent(inner) | Doc::Group(inner) => stack.push((mode, inner)),
+            Doc::Concat(docs) => {
+                for child in docs.iter().rev() {
+                    stack.push((mode, child));
+                }
+            }
+        }
+
+        if remaining < 0 {
+            return false;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn call(args: &[&str]) -> Doc {
+        let mut inner = vec![Doc::SoftLine];
+        for (i, arg) in args.iter().enumerate() {
+            if i > 0 {
+                inner.push(Doc::text(","));
+                inner.push(Doc::Line);
+            }
+            inner.push(Doc::text(*arg));
+        }
+        Doc::group(Doc::Concat(vec![
+            Doc::text("f("),
+            Doc::indent(Doc::Concat(inner)),
+            Doc::SoftLine,
+            Doc::text(")"),
+        ]))
+    }
+
+    #[test]
+    fn group_stays_flat_when_it_fits() {
+        assert_eq!(print(&call(&["a", "b"]), 80, 2), "f(a, b)");
+    }
+
+    #[test]
+    fn width_is_measured_in_characters() {
+        // 7 characters but 13 bytes
+        assert_eq!(print(&call(&["äö", "üß"]), 10, 2), "f(äö, üß)");
+    }
+
+    #[test]
+    fn group_breaks_when_too_wide() {
+        assert_eq!(
+            print(&call(&["aaaa", "bbbb"]), 10, 2),
+            "f(\n  aaaa,\n  bbbb\n)"
+        );
+    }
+
+    #[test]
+    fn hard_line_forces_enclosing_group_to_break() {
+        let doc = Doc::group(Doc::Concat(vec![
+            Doc::text("a"),
+            Doc::Line,
+            Doc::text("b"),
+            Doc::HardLine,
+        ]));
+        assert_eq!(print(&doc, 80, 2), "a\nb\n");
+    }
+
+    #[test]
+    fn line_suffix_moves_to_end_of_line_and_breaks_group() {
+        let doc = Doc::group(Doc::Concat(vec![
+            Doc::text("a,"),
+            Doc::LineSuffix(" // c".to_string()),
+            Doc::Line,
+            Doc::text("b"),
+        ]));
+        assert_eq!(print(&doc, 80, 2), "a, // c\nb");
+    }
+
+    #[test]
+    fn if_break_depends_on_the_enclosing_group() {
+        let doc = |width| {
+            let union = Doc::group(Doc::Concat(vec![
+                Doc::text("("),
+                Doc::if_break(Doc::text(" "), Doc::text("")),
+                Doc::text("A"),
+                Doc::Line,
+                Doc::text("| B"),
+                Doc::SoftLine,
+                Doc::text(")"),
+            ]));
+            print(&union, width, 2)
+        };
+        assert_eq!(doc(80), "(A | B)");
+        assert_eq!(doc(5), "( A\n| B\n)");
+    }
+
+    #[test]
+    fn blank_lines_have_no_trailing_indentation() {
+        let doc = Doc::Concat(vec![
+            Doc::text("a"),
+            Doc::indent(Doc::Concat(vec![
+                Doc::HardLine,
+                Doc::HardLine,
+                Doc::text("b"),
+            ])),
+        ]);
+        assert_eq!(print(&doc, 80, 2), "a\n\n  b");
+    }
+}
@@ -0,0 +1,356 @@
+//! Safety check that formatting did not change the program
+//!
+//! Before formatted output replaces a file it is parsed again and its token
+//! stream is compared with the token stream of the original source.
+//! Whitespace and comments are ignored, so any remaining difference means the
+//! formatter altered the program (for example by splitting a string literal or
+//! regrouping nested call arguments) and the output must not be written.
+//!
+//! # Example
+//!
+//! ```rust
+//! use ponyfmt::equivalence::check_equivalence;
+//!
+//! let original = "actor Main\nnew create(env: Env) =>\nenv.out.print(\"a, b\")";
+//! let formatted = "actor Main\n  new create(env: Env) =>\n    env.out.print(\"a, b\")\n";
+//! assert!(check_equivalence(original, formatted).is_ok());
+//!
+//! let mangled = "actor Main\n  new create(env: Env) =>\n    env.out.print(\"a\", \"b\")\n";
+//! assert!(check_equivalence(original, mangled).is_err());
+//! ```
+
+use crate::diagnostics::syntax_errors;
+use crate::error::{FormatError, Span, column};
+use crate::formatter::is_docstring;
+use crate::parser::parse;
+use std::fmt;
+use tree_sitter::Node;
+
+/// A token of the source together with where it was found
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Token {
+    /// Source text of the token
+    pub text: String,
+    /// Kind of the syntax node the token belongs to (e.g. `arguments`)
+    pub parent_kind: String,
+    /// 1-based line number
+    pub line: usize,
+    /// 1-based column number, counted in characters
+    pub column: usize,
+    /// Whether the token starts an expression of a block on a later line
+    /// than the expression before it; ponyc needs a `;` between expressions
+    /// on one line
+    pub on_new_line: bool,
+}
+
+impl fmt::Display for Token {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "`{}` ({}) at {}:{}",
+            self.text, self.parent_kind, self.line, self.column
+        )
+    }
+}
+
+/// Reason why formatted output is not equivalent to its input
+#[derive(Debug)]
+pub enum EquivalenceError {
+    /// The token at the same position differs between input and output, in
+    /// its text or in the kind of node it belongs to
+    Changed { original: Token, formatted: Token },
+    /// An expression of a block moved onto, or off, the line of the
+    /// expression before it
+    LineChanged { original: Token, formatted: Token },
+    /// A token of the input has no counterpart in the output
+    Removed { original: Token },
+    /// The output has a token the input did not have
+    Added { formatted: Token },
+    /// A comment of the input does not appear in the output
+    CommentLost { original: Token },
+    /// The output contains syntax errors that the input did not have
+    NewSyntaxError {
+        /// Message of the first new error, e.g. ``missing `end` ``
+        kind: String,
+        line: usize,
+        column: usize,
+    },
+}
+
+impl fmt::Display for EquivalenceError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            EquivalenceError::Changed {
+                original,
+                formatted,
+            } => write!(
+                f,
+                "formatting would change {} into {} of the output",
+                original, formatted
+            ),
+            EquivalenceError::LineChanged {
+                original,
+                formatted,
+            } if original.on_new_line => write!(
+                f,
+                "formatting would join {} onto the line of the expression before it, as {} of the output",
+                original, formatted
+            ),
+            EquivalenceError::LineChanged {
+                original,
+                formatted,
+            } => write!(
+                f,
+                "formatting would move {} off the line of the expression before it, as {} of the output",
+                original, formatted
+            ),
+            EquivalenceError::Removed { original } => {
+                write!(f, "formatting would remove {}", original)
+            }
+            EquivalenceError::Added { formatted } => {
+                write!(f, "formatting would add {} of the output", formatted)
+            }
+            EquivalenceError::CommentLost { original } => {
+                write!(f, "formatting would lose the comment {}", original)
+            }
+            EquivalenceError::NewSyntaxError { kind, line, column } => write!(
+                f,
+                "formatted output has a syntax error ({}) at {}:{} that the input did not have",
+                kind, line, column
+            ),
+        }
+    }
+}
+
+impl std::error::Error for EquivalenceError {}
+
+impl From<EquivalenceError> for FormatError {
+    fn from(error: EquivalenceError) -> Self {
+        // Point at the input where possible, since that is what the user edits
+        let span = match &error {
+            EquivalenceError::Changed { original, .. }
+            | EquivalenceError::LineChanged { original, .. }
+            | EquivalenceError::Removed { original }
+            | EquivalenceError::CommentLost { original } => Span::at(
+                original.line,
+                original.column,
+                original.text.chars().count(),
+            ),
+            EquivalenceError::Added { formatted } => Span::at(
+                formatted.line,
+                formatted.column,
+                formatted.text.chars().count(),
+            ),
+            EquivalenceError::NewSyntaxError { line, column, .. } => Span::at(*line, *column, 0),
+        };
+        FormatError::NotEquivalent {
+            path: None,
+            span,
+            error: Box::new(error),
+        }
+    }
+}
+
+/// Check that `formatted` is the same program as `original`
+///
+/// Both sources are parsed and their tokens compared in order, by text and
+/// by the kind of node they belong to, ignoring whitespace and comments. The
+/// only line breaks compared are those between the expressions of a block,
+/// which ponyc needs. Whitespace inside docstrings is compared as single
+/// spaces, since the formatter re-indents and may re-wrap them.
+/// Tokens tree-sitter inserted to recover from syntax errors (`MISSING`
+/// nodes) are ignored on both sides. Comments may
+/// move, but every comment of the input must still be in the output. A run
+/// of adjacent `use` statements may be reordered. Returns a
+/// [`FormatError::NotEquivalent`] wrapping an [`EquivalenceError`] that
+/// describes the first difference.
+pub fn check_equivalence(original: &str, formatted: &str) -> Result<(), FormatError> {
+    let original_tree = parse(original)?;
+    let formatted_tree = parse(formatted)?;
+
+    let original_errors = syntax_errors(original_tree.root_node(), original);
+    let formatted_errors = syntax_errors(formatted_tree.root_node(), formatted);
+    if formatted_errors.len() > original_errors.len() {
+        // Report the first error that does not line up with one of the input
+        let (_, new_error) = formatted_errors
+            .iter()
+            .enumerate()
+            .find(|(i, error)| {
+                original_errors
+                    .get(*i)
+                    .is_none_or(|o| o.message != error.message)
+            })
+            .expect("the output has more errors than the input");
+        return Err(EquivalenceError::NewSyntaxError {
+            kind: new_error.message.clone(),
+            line: new_error.span.line,
+            column: new_error.span.column,
+        }
+        .into());
+    }
+
+    let original_tokens = tokens(original_tree.root_node(), original.as_bytes());
+    let formatted_tokens = tokens(formatted_tree.root_node(), formatted.as_bytes());
+
+    for (i, original_token) in original_tokens.iter().enumerate() {
+        match formatted_tokens.get(i) {
+            Some(formatted_token)
+                if formatted_token.text != original_token.text
+                    || formatted_token.parent_kind != original_token.parent_kind =>
+            {
+                return Err(EquivalenceError::Changed {
+                    original: original_token.clone(),
+                    formatted: formatted_token.clone(),
+                }
+                .into());
+            }
+            Some(formatted_token) if formatted_token.on_new_line != original_token.on_new_line => {
+                return Err(EquivalenceError::LineChanged {
+                    original: original_token.clone(),
+                    formatted: formatted_token.clone(),
+                }
+                .into());
+            }
+            Some(_) => {}
+            None => {
+                return Err(EquivalenceError::Removed {
+                    original: original_token.clone(),
+                }
+                .into());
+            }
+        }
+    }
+    if let Some(extra) = formatted_tokens.get(original_tokens.len()) {
+        return Err(EquivalenceError::Added {
+            formatted: extra.clone(),
+        }
+        .into());
+    }
+
+    let mut formatted_comments = comments(formatted_tree.root_node(), formatted.as_bytes());
+    for comment in comments(original_tree.root_node(), original.as_bytes()) {
+        match formatted_comments
+            .iter()
+            .position(|c| c.text == comment.text)
+        {
+            Some(i) => {
+                formatted_comments.remove(i);
+            }
+            None => return Err(EquivalenceError::CommentLost { original: comment }.into()),
+        }
+    }
+
+    Ok(())
+}
+
+/// Collect the significant tokens of the file below `root` in source order
+///
+/// The formatter may sort adjacent `use` statements, so each run of them is
+/// compared as an unordered set: its statements are put in the order of
+/// their tokens on both sides.
+fn tokens(root: Node, source: &[u8]) -> Vec<Token> {
+    let mut out = Vec::new();
+    let mut uses: Vec<Vec<Token>> = Vec::new();
+    for child in root.children(&mut root.walk()) {
+        if child.kind() == "use_statement" {
+            let mut statement = Vec::new();
+            collect_tokens(child, source, &mut statement);
+            uses.push(statement);
+            continue;
+        }
+        // Comments between `use` statements do not end the run
+        if !child.is_extra() || child.is_error() {
+            flush_uses(&mut uses, &mut out);
+        }
+        collect_tokens(child, source, &mut out);
+    }
+    flush_uses(&mut uses, &mut out);
+    out
+}
+
+/// Append a run of `use` statements to `out` in a canonical order
+fn flush_uses(uses: &mut Vec<Vec<Token>>, out: &mut Vec<Token>) {
+    uses.sort_by_cached_key(|statement| {
+        statement
+            .iter()
+            .map(|token| token.text.clone())
+            .collect::<Vec<_>>()
+    });
+    out.extend(uses.drain(..).flatten());
+}
+
+fn collect_tokens(node: Node, source: &[u8], out: &mut Vec<Token>) {
+    // Comments are compared separately; ERROR nodes can be extras too
+    if (node.is_extra() && !node.is_error()) || node.is_missing() {
+        return;
+    }
+    if node.child_count() == 0 {
+        let start = node.start_position();
+        let mut text =
+            String::from_utf8_lossy(&source[node.start_byte()..node.end_byte()]).to_string();
+        if is_docstring_content(node, source) {
+            // Docstrings are re-indented and may be re-wrapped
+            text = text.split_whitespace().collect::<Vec<_>>().join(" ");
+        }
+        out.push(Token {
+            text,
+            parent_kind: node
+                .parent()
+                .map_or_else(|| node.kind().to_string(), |p| p.kind().to_string()),
+            line: start.row + 1,
+            column: column(source, node.start_byte()),
+            on_new_line: false,
+        });
+        return;
+    }
+    let mut prev: Option<Node> = None;
+    for child in node.children(&mut node.walk()) {
+        let first = out.len();
+        collect_tokens(child, source, out);
+        if child.is_extra() {
+            continue;
+        }
+        if node.kind() == "block"
+            && let Some(prev) = prev
+            && prev.kind() != ";"
+            && let Some(token) = out.get_mut(first)
+        {
+            token.on_new_line = child.start_position().row > prev.end_position().row;
+        }
+        prev = Some(child);
+    }
+}
+
+/// Whether `node` is the text of a docstring
+fn is_docstring_content(node: Node, source: &[u8]) -> bool {
+    node.kind() == "string_content"
+        && node
+            .parent()
+            .is_some_and(|string| is_docstring(string, source))
+}
+
+/// Collect the comments below `node`, with surrounding whitespace trimmed
+fn comments(node: Node, source: &[u8]) -> Vec<Token> {
+    let mut out = Vec::new();
+    collect_comments(node, source, &mut out);
+    out
+}
+
+fn collect_comments(node: Node, source: &[u8], out: &mut Vec<Token>) {
+    if matches!(node.kind(), "line_comment" | "block_comment") {
+        let start = node.start_position();
+        out.push(Token {
+            text: String::from_utf8_lossy(&source[node.start_byte()..node.end_byte()])
+                .trim()
+                .to_string(),
+            parent_kind: node.kind().to_string(),
+            line: start.row + 1,
+            column: column(source, node.start_byte()),
+            on_new_line: false,
+        });
+        return;
+    }
+    for child in node.children(&mut node.walk()) {
+        collect_comments(child, source, out);
+    }
+}
@@ -0,0 +1,208 @@
+//! Errors returned by the parser and formatter
+//!
+//! Every failure of the library is a
+//! [`FormatError`](crate::error::FormatError), so callers can tell a grammar
+//! that failed to load from a formatter that produced unstable or unsafe
+//! output. Errors about a place in the source carry its
+//! [`Span`](crate::error::Span), and the path of the file once the caller
+//! attaches it with
+//! [`FormatError::with_path`](crate::error::FormatError::with_path); they are
+//! displayed as `path:line:col: message`, except syntax errors, which are
+//! rendered as source snippets.
+
+use crate::diagnostics::Diagnostic;
+use crate::equivalence::EquivalenceError;
+use std::fmt;
+use std::path::{Path, PathBuf};
+use tree_sitter::{LanguageError, Node};
+
+/// A region of the source, with 1-based lines and columns counted in
+/// characters
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Span {
+    pub line: usize,
+    pub column: usize,
+    pub end_line: usize,
+    pub end_column: usize,
+}
+
+impl Span {
+    /// A span of `len` columns starting at `line`:`column`
+    pub fn at(line: usize, column: usize, len: usize) -> Self {
+        Self {
+            line,
+            column,
+            end_line: line,
+            end_column: column + len,
+        }
+    }
+
+    /// The span of a syntax node of `source`
+    pub fn of(node: Node, source: &[u8]) -> Self {
+        Self {
+            line: node.start_position().row + 1,
+            column: column(source, node.start_byte()),
+            end_line: node.end_position().row + 1,
+            end_column: column(source, node.end_byte()),
+        }
+    }
+}
+
+/// The 1-based column of byte `offset` of `source`, counted in characters
+/// like the widths of the formatter
+pub(crate) fn column(source: &[u8], offset: usize) -> usize {
+    let line_start = source[..offset]
+        .iter()
+        .rposition(|&b| b == b'\n')
+        .map_or(0, |i| i + 1);
+    // Count every byte that does not continue a UTF-8 character
+    source[line_start..offset]
+        .iter()
+        .filter(|&&b| b & 0xC0 != 0x80)
+        .count()
+        + 1
+}
+
+/// Error returned by [`crate::parser::parse`] and the formatter
+#[derive(Debug)]
+pub enum FormatError {
+    /// The Pony grammar could not be loaded into tree-sitter
+    Language(LanguageError),
+    /// tree-sitter gave up and produced no syntax tree
+    Parse { path: Option<PathBuf> },
+    /// The source has syntax errors and `allow_errors` is not set
+    Syntax {
+        path: Option<PathBuf>,
+        /// Every ERROR and MISSING node of the tree, in source order
+        diagnostics: Vec<Diagnostic>,
+    },
+    /// Formatting the output a second time changed it
+    Unstable {
+        path: Option<PathBuf>,
+        /// First line of the formatted output (not of the input) that the
+        /// second pass changed, 1-based
+        output_line: usize,
+        /// Unified diff from the first formatting pass to the second
+        diff: String,
+    },
+    /// The formatted output is not the same program as the input
+    NotEquivalent {
+        path: Option<PathBuf>,
+        /// Where the difference starts in the input
+        span: Span,
+        /// The difference, with the tokens and syntax node kinds involved
+        error: Box<EquivalenceError>,
+    },
+    /// A configuration file could not be read or has an invalid value
+    Config {
+        /// The configuration file
+        path: Option<PathBuf>,
+        message: String,
+    },
+}
+
+impl FormatError {
+    /// Attach the path of the file the error is about
+    pub fn with_path(mut self, file: &Path) -> Self {
+        match &mut self {
+            FormatError::Language(_) => {}
+            FormatError::Parse { path }
+            | FormatError::Syntax { path, .. }
+            | FormatError::Unstable { path, .. }
+            | FormatError::NotEquivalent { path, .. }
+            | FormatError::Config { path, .. } => *path = Some(file.to_path_buf()),
+        }
+        self
+    }
+
+    /// Path of the file the error is about, if known
+    pub fn path(&self) -> Option<&Path> {
+        match self {
+            FormatError::Language(_) => None,
+            FormatError::Parse { path }
+            | FormatError::Syntax { path, .. }
+            | FormatError::Unstable { path, .. }
+            | FormatError::NotEquivalent { path, .. }
+            | FormatError::Config { path, .. } => path.as_deref(),
+        }
+    }
+
+    /// Source region the error is about, if any
+    pub fn span(&self) -> Option<Span> {
+        match self {
+            // The line of an instability is in the output, not the source
+            FormatError::Language(_)
+            | FormatError::Parse { .. }
+            | FormatError::Unstable { .. }
+            | FormatError::Config { .. } => None,
+            FormatError::Syntax { diagnostics, .. } => diagnostics.first().map(|d| d.span),
+            // So is a syntax error the formatter introduced
+            FormatError::NotEquivalent { error, .. }
+                if matches!(**error, EquivalenceError::NewSyntaxError { .. }) =>
+            {
+                None
+            }
+            FormatError::NotEquivalent { span, .. } => Some(*span),
+        }
+    }
+}
+
+impl fmt::Display for FormatError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        // `path:line:col: ` with whatever parts are known
+        let mut location = Vec::new();
+        if let Some(path) = self.path() {
+            location.push(path.display().to_string());
+        }
+        if let Some(span) = self.span() {
+            location.push(span.line.to_string());
+            location.push(span.column.to_string());
+        }
+        // Syntax errors carry their own locations in rustc-style snippets
+        if !location.is_empty() && !matches!(self, FormatError::Syntax { .. }) {
+            write!(f, "{}: ", location.join(":"))?;
+        }
+
+        match self {
+            FormatError::Language(error) => {
+                write!(f, "failed to load the Pony grammar: {}", error)
+            }
+            FormatError::Parse { .. } => write!(f, "failed to parse Pony source"),
+            FormatError::Syntax { path, diagnostics } => {
+                let rendered: Vec<String> = diagnostics
+                    .iter()
+                    .map(|d| d.render(path.as_deref()))
+                    .collect();
+                write!(f, "{}", rendered.join("\n\n"))
+            }
+            FormatError::Unstable {
+                output_line, diff, ..
+            } => {
+                writeln!(
+                    f,
+                    "formatting is not idempotent; a second pass changed line {} of the formatted output:",
+                    output_line
+                )?;
+                write!(f, "{}", diff)
+            }
+            FormatError::NotEquivalent { error, .. } => write!(f, "{}", error),
+            FormatError::Config { message, .. } => write!(f, "{}", message),
+        }
+    }
+}
+
+impl std::error::Error for FormatError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            FormatError::Language(error) => Some(error),
+            FormatError::NotEquivalent { error, .. } => Some(error.as_ref()),
+            _ => None,
+        }
+    }
+}
+
+impl From<LanguageError> for FormatError {
+    fn from(error: LanguageError) -> Self {
+        FormatError::Language(error)
+    }
+}
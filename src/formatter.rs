@@ -7,8 +7,14 @@
 //! - Proper spacing around operators and keywords
 //! - Class/actor members indented within their containers
 
+use crate::diagnostics::syntax_errors;
+use crate::doc::{self, Doc};
+use crate::equivalence::EquivalenceError;
+use crate::error::FormatError;
 use crate::parser::parse;
-use anyhow::Result;
+use crate::trivia::{Comment, Placement, Trivia};
+use similar::TextDiff;
+use std::ops::Range;
 use tree_sitter::Node;
 
 /// Output mode for the formatter
@@ -20,60 +26,183 @@ pub enum Mode {
     Write,
     /// Check if formatting would change the code (used for CI/validation)
     Check,
+    /// Print a unified diff of the changes formatting would make
+    Diff,
 }
 
 /// Configuration options for the formatter
+#[derive(Clone, Debug)]
 pub struct FormatOptions {
     /// Number of spaces to use for each indentation level (defaults to 2 for Pony)
     pub indent_width: usize,
+    /// Maximum line width the layout engine tries to stay within
+    pub max_width: usize,
+    /// Maximum number of consecutive blank lines kept between members and
+    /// between statements; extra blank lines are removed
+    pub max_blank_lines: usize,
+    /// Sort consecutive `use` statements alphabetically
+    pub sort_uses: bool,
+    /// Re-wrap the prose paragraphs of docstrings to the maximum line width
+    pub wrap_docstrings: bool,
     /// How to handle the formatted output
     pub mode: Mode,
+    /// Format the output a second time and fail if it changes
+    pub verify: bool,
+    /// Format source with syntax errors instead of failing with
+    /// [`FormatError::Syntax`]
+    pub allow_errors: bool,
 }
 
 impl Default for FormatOptions {
     fn default() -> Self {
         Self {
             indent_width: 2,
+            max_width: 100,
+            max_blank_lines: 1,
+            sort_uses: false,
+            wrap_docstrings: false,
             mode: Mode::Stdout,
+            verify: false,
+            allow_errors: false,
         }
     }
 }
 
-/// Simple formatter state that tracks indentation and output
+#[derive(Debug, PartialEq, Eq)]
+enum FrameKind {
+    Root,
+    Indent,
+    Group,
+}
+
+/// Formatter state that builds a [`Doc`] for the whole file
+///
+/// Formatting code writes text and line breaks as if it were emitting a
+/// string; indentation and groups open nested frames that are wrapped in
+/// [`Doc::Indent`] / [`Doc::Group`] when closed. The layout decisions are
+/// made afterwards by [`doc::print`].
+///
+/// Comments are emitted from [`Trivia`] by source position: before each node
+/// the comments preceding it are written, and at every line break the
+/// comments trailing the code written last are attached to the line.
 #[derive(Debug)]
 struct FormatterState {
-    output: String,
-    indent_level: usize,
+    frames: Vec<(FrameKind, Vec<Doc>)>,
     current_line_has_content: bool,
+    trivia: Trivia,
+    /// End byte of the source written most recently
+    last_end: usize,
+    /// Comments to attach to the current line along with those trailing
+    /// the code written last
+    deferred_comments: Vec<Comment>,
 }
 
 impl FormatterState {
-    fn new() -> Self {
+    fn new(trivia: Trivia) -> Self {
         Self {
-            output: String::new(),
-            indent_level: 0,
+            frames: vec![(FrameKind::Root, Vec::new())],
             current_line_has_content: false,
+            trivia,
+            last_end: 0,
+            deferred_comments: Vec::new(),
         }
     }
 
-    fn write_indent(&mut self, opts: &FormatOptions) {
-        if !self.current_line_has_content {
-            for _ in 0..(self.indent_level * opts.indent_width) {
-                self.output.push(' ');
-            }
+    fn push(&mut self, doc: Doc) {
+        if let Some((_, docs)) = self.frames.last_mut() {
+            docs.push(doc);
         }
     }
 
     fn write_text(&mut self, text: &str) {
-        self.output.push_str(text);
+        self.push(Doc::text(text));
         self.current_line_has_content = true;
     }
 
+    /// Write the source text of `node` verbatim, including any comments in it
+    fn write_node(&mut self, node: Node, source: &[u8]) {
+        self.write_source(node.start_byte(), node.end_byte(), source);
+    }
+
+    /// Write the source text of `start..end` verbatim, including any comments in it
+    fn write_source(&mut self, start: usize, end: usize, source: &[u8]) {
+        self.write_comments_before(start);
+        self.write_text(&String::from_utf8_lossy(&source[start..end]));
+        self.trivia.consume_range(start, end);
+        self.mark_end(end);
+    }
+
+    /// Record that the source up to `pos` has been written
+    fn mark_end(&mut self, pos: usize) {
+        self.last_end = self.last_end.max(pos);
+    }
+
+    /// Write the comments that end before `pos` and have not been written yet
+    fn write_comments_before(&mut self, pos: usize) {
+        for comment in self.trivia.take_before(pos) {
+            match comment.placement {
+                Placement::Inline => {
+                    self.write_text(&comment.text);
+                    self.write_text(" ");
+                }
+                // Attached where a second pass finds it, after the code that
+                // ends the line, so that both passes break the line alike
+                Placement::EndOfLine if self.current_line_has_content => {
+                    self.deferred_comments.push(comment);
+                }
+                Placement::EndOfLine | Placement::OwnLine => {
+                    self.write_text(&comment.text);
+                    self.push(Doc::HardLine);
+                    self.current_line_has_content = false;
+                }
+            }
+        }
+    }
+
+    /// Attach comments trailing the code written last to the current line
+    fn write_trailing_comments(&mut self) {
+        let mut comments = std::mem::take(&mut self.deferred_comments);
+        comments.extend(self.trivia.take_trailing(self.last_end));
+        for comment in comments {
+            self.push(Doc::LineSuffix(format!(" {}", comment.text)));
+        }
+    }
+
     fn write_newline(&mut self) {
-        self.output.push('\n');
+        self.write_trailing_comments();
+        self.push(Doc::HardLine);
         self.current_line_has_content = false;
     }
 
+    /// A space, or a line break if the enclosing group does not fit
+    fn write_line(&mut self) {
+        self.write_trailing_comments();
+        self.push(Doc::Line);
+    }
+
+    /// Nothing, or a line break if the enclosing group does not fit
+    fn write_softline(&mut self) {
+        self.write_trailing_comments();
+        self.push(Doc::SoftLine);
+    }
+
+    /// `broken` if the enclosing group does not fit, otherwise `flat`
+    fn write_if_break(&mut self, broken: &str, flat: &str) {
+        self.push(Doc::if_break(Doc::text(broken), Doc::text(flat)));
+        self.current_line_has_content = true;
+    }
+
+    /// Undo the line break just written, if nothing followed it
+    fn reopen_line(&mut self) -> bool {
+        if let Some((_, docs)) = self.frames.last_mut()
+            && pop_hard_line(docs)
+        {
+            self.current_line_has_content = true;
+            return true;
+        }
+        false
+    }
+
     fn write_blank_line(&mut self) {
         if self.current_line_has_content {
             self.write_newline();
@@ -81,227 +210,601 @@ impl FormatterState {
         self.write_newline();
     }
 
+    /// Keep up to `opts.max_blank_lines` of the blank lines the source had
+    /// between the end of `prev` and the start of `node`
+    fn write_preserved_blank_lines(
+        &mut self,
+        prev: Option<Node>,
+        node: Node,
+        source: &[u8],
+        opts: &FormatOptions,
+    ) {
+        let Some(prev) = prev else {
+            return;
+        };
+        let gap = String::from_utf8_lossy(&source[prev.end_byte()..node.start_byte()]);
+        let lines: Vec<_> = gap.split('\n').collect();
+        let blank_lines = lines
+            .iter()
+            .take(lines.len().saturating_sub(1))
+            .skip(1)
+            .filter(|line| line.trim().is_empty())
+            .count();
+        for _ in 0..blank_lines.min(opts.max_blank_lines) {
+            self.write_blank_line();
+        }
+    }
+
+    /// Number of indentation levels the next line will be indented by
+    fn indent_level(&self) -> usize {
+        self.frames
+            .iter()
+            .filter(|(kind, _)| *kind == FrameKind::Indent)
+            .count()
+    }
+
     fn increase_indent(&mut self) {
-        self.indent_level += 1;
+        self.frames.push((FrameKind::Indent, Vec::new()));
     }
 
     fn decrease_indent(&mut self) {
-        if self.indent_level > 0 {
-            self.indent_level -= 1;
+        self.close_frame(FrameKind::Indent);
+    }
+
+    fn begin_group(&mut self) {
+        self.frames.push((FrameKind::Group, Vec::new()));
+    }
+
+    fn end_group(&mut self) {
+        self.close_frame(FrameKind::Group);
+    }
+
+    fn close_frame(&mut self, kind: FrameKind) {
+        if self.frames.last().is_some_and(|(k, _)| *k == kind) {
+            let (kind, docs) = self.frames.pop().expect("frame checked above");
+            let doc = match kind {
+                FrameKind::Indent => Doc::indent(Doc::Concat(docs)),
+                FrameKind::Group => Doc::group(Doc::Concat(docs)),
+                FrameKind::Root => unreachable!("the root frame is never closed"),
+            };
+            self.push(doc);
         }
     }
+
+    /// Close any frames left open (e.g. by error recovery) and return the document
+    fn into_doc(mut self) -> Doc {
+        while self.frames.len() > 1 {
+            let kind = match self.frames.last() {
+                Some((FrameKind::Group, _)) => FrameKind::Group,
+                _ => FrameKind::Indent,
+            };
+            self.close_frame(kind);
+        }
+        let (_, docs) = self.frames.pop().expect("root frame is always present");
+        Doc::Concat(docs)
+    }
+}
+
+/// Remove the line break ending `docs`, including one that ends an indented
+/// block closed just before
+fn pop_hard_line(docs: &mut Vec<Doc>) -> bool {
+    match docs.last_mut() {
+        Some(Doc::HardLine) => {
+            docs.pop();
+            true
+        }
+        Some(Doc::Indent(inner)) => match inner.as_mut() {
+            Doc::Concat(docs) => pop_hard_line(docs),
+            _ => false,
+        },
+        _ => false,
+    }
 }
 
 /// Format Pony source code according to style conventions
-pub fn format_source(input: &str, opts: &FormatOptions) -> Result<String> {
+///
+/// When [`FormatOptions::verify`] is set the result is formatted a second
+/// time, and a [`FormatError::Unstable`] error is returned if the two passes
+/// disagree. Syntax errors in the result that the input did not have are
+/// returned as [`FormatError::NotEquivalent`].
+pub fn format_source(input: &str, opts: &FormatOptions) -> Result<String, FormatError> {
+    let formatted = format_once(input, opts)?;
+    if opts.verify {
+        verify(&formatted, opts, format_once)?;
+    }
+    Ok(formatted)
+}
+
+/// Check that formatting `formatted` again with `reformat` leaves it as it is
+fn verify(
+    formatted: &str,
+    opts: &FormatOptions,
+    reformat: impl FnOnce(&str, &FormatOptions) -> Result<String, FormatError>,
+) -> Result<(), FormatError> {
+    // Syntax errors of the output are the formatter's, not the input's
+    if !opts.allow_errors {
+        let tree = parse(formatted)?;
+        if let Some(error) = syntax_errors(tree.root_node(), formatted).first() {
+            return Err(EquivalenceError::NewSyntaxError {
+                kind: error.message.clone(),
+                line: error.span.line,
+                column: error.span.column,
+            }
+            .into());
+        }
+    }
+    let second_pass = FormatOptions {
+        allow_errors: true,
+        verify: false,
+        ..opts.clone()
+    };
+    let reformatted = reformat(formatted, &second_pass)?;
+    if reformatted != formatted {
+        let diff = TextDiff::from_lines(formatted, &reformatted)
+            .unified_diff()
+            .header("pass 1", "pass 2")
+            .to_string();
+        let line = formatted
+            .lines()
+            .zip(reformatted.lines())
+            .take_while(|(a, b)| a == b)
+            .count()
+            + 1;
+        return Err(FormatError::Unstable {
+            path: None,
+            output_line: line,
+            diff,
+        });
+    }
+    Ok(())
+}
+
+fn format_once(input: &str, opts: &FormatOptions) -> Result<String, FormatError> {
     let tree = parse(input)?;
     let root_node = tree.root_node();
-    let mut state = FormatterState::new();
+    check_syntax(root_node, input, opts)?;
+    let mut state = FormatterState::new(Trivia::collect(root_node, input.as_bytes()));
 
     format_node(root_node, input.as_bytes(), &mut state, opts);
 
-    Ok(state.output)
+    // Comments no node claimed end up at the end of the file
+    state.write_trailing_comments();
+    if state.current_line_has_content {
+        state.write_newline();
+    }
+    state.write_comments_before(usize::MAX);
+
+    Ok(doc::print(
+        &state.into_doc(),
+        opts.max_width,
+        opts.indent_width,
+    ))
+}
+
+/// Fail with every syntax error in the tree unless `allow_errors` is set
+fn check_syntax(root: Node, input: &str, opts: &FormatOptions) -> Result<(), FormatError> {
+    if opts.allow_errors || !root.has_error() {
+        return Ok(());
+    }
+    Err(FormatError::Syntax {
+        path: None,
+        diagnostics: syntax_errors(root, input),
+    })
+}
+
+/// Format only the part of `input` covering the byte `range`
+///
+/// The range is widened to the smallest run of complete top-level
+/// declarations, members or statements that covers it and starts and ends on
+/// line boundaries. That run is formatted at the indentation of its first
+/// line; the rest of the source is returned unchanged. An empty range formats
+/// the unit containing its position. [`FormatOptions::verify`] formats the
+/// same part of the result again, as [`format_source`] does for a whole file.
+pub fn format_range(
+    input: &str,
+    range: Range<usize>,
+    opts: &FormatOptions,
+) -> Result<String, FormatError> {
+    let tree = parse(input)?;
+    let root_node = tree.root_node();
+    check_syntax(root_node, input, opts)?;
+    let source = input.as_bytes();
+
+    let Some((container, children)) = range_units(root_node, source, range) else {
+        return format_source(input, opts);
+    };
+    let first = children[0];
+    let last = children[children.len() - 1];
+    let start = line_start(source, first.start_byte());
+    let end = source[last.end_byte()..]
+        .iter()
+        .position(|&b| b == b'\n')
+        .map_or(source.len(), |i| last.end_byte() + i + 1);
+
+    // Only comments inside the region are written
+    let mut trivia = Trivia::collect(root_node, source);
+    trivia.consume_range(0, start);
+    trivia.consume_range(end, usize::MAX);
+    let mut state = FormatterState::new(trivia);
+
+    let indent_columns = source[start..first.start_byte()].len();
+    let indent_levels = indent_columns / opts.indent_width.max(1);
+    for _ in 0..indent_levels {
+        state.increase_indent();
+    }
+    match container.kind() {
+        "members" => format_members(&children, source, &mut state, opts),
+        "block" => format_statements(&children, source, &mut state, opts),
+        _ => format_top_level(&children, source, &mut state, opts),
+    }
+    state.write_trailing_comments();
+    if state.current_line_has_content {
+        state.write_newline();
+    }
+    state.write_comments_before(end);
+
+    // The printer only indents lines after the first
+    let formatted = doc::print(&state.into_doc(), opts.max_width, opts.indent_width);
+    let indent = " ".repeat(indent_levels * opts.indent_width);
+    let result = format!(
+        "{}{}{}{}",
+        &input[..start],
+        indent,
+        formatted,
+        &input[end..]
+    );
+    if opts.verify {
+        let region = start..start + indent.len() + formatted.len();
+        verify(&result, opts, |text, opts| format_range(text, region, opts))?;
+    }
+    Ok(result)
+}
+
+/// Find the container node and the run of its children to reformat for `range`
+///
+/// Returns `None` if no run of whole lines covers the range, in which case
+/// the whole file has to be formatted.
+fn range_units<'a>(
+    root: Node<'a>,
+    source: &[u8],
+    range: Range<usize>,
+) -> Option<(Node<'a>, Vec<Node<'a>>)> {
+    let is_container = |node: &Node| matches!(node.kind(), "source_file" | "members" | "block");
+    // Whitespace around the range does not belong to any unit
+    let mut start = range.start.min(source.len());
+    let mut end = range.end.clamp(start, source.len());
+    while start < end && source[start].is_ascii_whitespace() {
+        start += 1;
+    }
+    // Nor do comments ending it, which may sit outside the node they trail
+    loop {
+        while end > start && source[end - 1].is_ascii_whitespace() {
+            end -= 1;
+        }
+        match root.descendant_for_byte_range(end.saturating_sub(1), end) {
+            Some(node) if end > start && is_comment(node) && node.start_byte() > start => {
+                end = node.start_byte();
+            }
+            _ => break,
+        }
+    }
+    let mut container = root.descendant_for_byte_range(start, end)?;
+    while !is_container(&container) {
+        container = container.parent()?;
+    }
+
+    loop {
+        let children: Vec<_> = container
+            .children(&mut container.walk())
+            .filter(|child| !is_comment(*child) && child.kind() != ";")
+            .filter(|child| child.end_byte() >= start && child.start_byte() <= end)
+            .collect();
+        if let (Some(first), Some(last)) = (children.first(), children.last())
+            && starts_line(source, first.start_byte())
+            && ends_line(source, last.end_byte())
+        {
+            return Some((container, children));
+        }
+
+        // Widen the range to the unit enclosing this container
+        start = container.start_byte();
+        end = container.end_byte();
+        container = container.parent()?;
+        while !is_container(&container) {
+            container = container.parent()?;
+        }
+    }
+}
+
+/// Byte offset of the start of the line containing `pos`
+fn line_start(source: &[u8], pos: usize) -> usize {
+    source[..pos]
+        .iter()
+        .rposition(|&b| b == b'\n')
+        .map_or(0, |i| i + 1)
 }
 
-fn format_arguments(node: Node, source: &[u8], state: &mut FormatterState, _opts: &FormatOptions) {
-    // Extract all the text content and reformat on a single line
-    let full_text = node_text(node, source);
+/// Whether only whitespace precedes `pos` on its line
+fn starts_line(source: &[u8], pos: usize) -> bool {
+    source[line_start(source, pos)..pos]
+        .iter()
+        .all(|b| b.is_ascii_whitespace())
+}
 
-    // Remove the parentheses and extract the content
-    let content = full_text.strip_prefix('(').unwrap_or(&full_text);
-    let content = content.strip_suffix(')').unwrap_or(content);
+/// Whether only whitespace or a comment follows `pos` on its line
+fn ends_line(source: &[u8], pos: usize) -> bool {
+    let rest = source[pos..].split(|&b| b == b'\n').next().unwrap_or(&[]);
+    let rest = rest.trim_ascii_start();
+    rest.is_empty() || rest.starts_with(b"//") || rest.starts_with(b"/*")
+}
 
-    // Split by commas and clean up each argument
-    let args: Vec<&str> = content
-        .split(',')
-        .map(|arg| arg.trim())
-        .filter(|arg| !arg.is_empty())
-        .collect();
+fn format_arguments(node: Node, source: &[u8], state: &mut FormatterState, opts: &FormatOptions) {
+    // Argument lists with syntax errors are kept as written
+    if node.has_error() {
+        state.write_node(node, source);
+        return;
+    }
+    let (named, args): (Vec<_>, Vec<_>) = node
+        .named_children(&mut node.walk())
+        .filter(|child| !is_comment(*child))
+        .partition(|child| child.kind() == "named_arguments");
+
+    if args.is_empty() && named.is_empty() {
+        state.write_text("()");
+        state.mark_end(node.end_byte());
+        return;
+    }
 
-    // Write formatted arguments
+    // Lay the arguments out on one line if they fit, otherwise one per line
+    state.begin_group();
     state.write_text("(");
+    state.increase_indent();
+    state.write_softline();
     for (i, arg) in args.iter().enumerate() {
         if i > 0 {
-            state.write_text(", ");
+            state.write_text(",");
+            state.write_line();
+        }
+        format_expression(*arg, source, state, opts);
+    }
+    // `where name = value, ...` follows the positional arguments without a comma
+    for named in named {
+        if !args.is_empty() {
+            state.write_line();
         }
-        state.write_text(arg);
+        format_named_arguments(named, source, state, opts);
     }
+    state.decrease_indent();
+    state.write_softline();
     state.write_text(")");
+    state.mark_end(node.end_byte());
+    state.end_group();
 }
 
-fn format_node(node: Node, source: &[u8], state: &mut FormatterState, opts: &FormatOptions) {
-    match node.kind() {
-        "source_file" => {
-            // Handle the root of the file
-
-            let mut prev_kind: Option<&str> = None;
+/// Format the `where name = value, ...` part of an argument list
+fn format_named_arguments(
+    node: Node,
+    source: &[u8],
+    state: &mut FormatterState,
+    opts: &FormatOptions,
+) {
+    for child in node.children(&mut node.walk()) {
+        match child.kind() {
+            "where" => {
+                state.write_node(child, source);
+                state.write_text(" ");
+            }
+            "," => {
+                state.write_text(",");
+                state.write_line();
+            }
+            "named_argument" => format_separated(child, source, state, opts),
+            _ if !is_comment(child) => format_expression(child, source, state, opts),
+            _ => {}
+        }
+    }
+}
 
-            for child in node.children(&mut node.walk()) {
-                let current_kind = child.kind();
-
-                // Add blank lines between different types of top-level declarations
-                if let Some(prev) = prev_kind {
-                    let needs_blank_line = match (prev, current_kind) {
-                        // Always add blank line after block comments (unless next is also a comment)
-                        ("block_comment", kind)
-                            if kind != "block_comment" && kind != "line_comment" =>
-                        {
-                            true
-                        }
-                        // Add blank lines between different declaration types
-                        ("primitive_definition", kind)
-                            if kind != "primitive_definition" && kind != "line_comment" =>
-                        {
-                            true
-                        }
-                        ("type_alias", kind) if kind != "type_alias" && kind != "line_comment" => {
-                            true
-                        }
-                        ("trait_definition", kind)
-                            if kind != "trait_definition" && kind != "line_comment" =>
-                        {
-                            true
-                        }
-                        ("class_definition", kind) if kind != "line_comment" => true,
-                        // Add blank line before line comments that come after class definitions
-                        ("class_definition", "line_comment")
-                        | ("trait_definition", "line_comment")
-                        | ("primitive_definition", "line_comment") => true,
-                        // Add blank line before first declaration after use statements
-                        ("use_statement", kind)
-                            if kind != "use_statement" && kind != "line_comment" =>
-                        {
-                            true
-                        }
-                        _ => false,
-                    };
+/// Format the parameters of a method on one line if the enclosing group
+/// fits, otherwise one per line indented below the method name
+///
+/// The caller opens the group, so that it can include the return type.
+fn format_parameters(node: Node, source: &[u8], state: &mut FormatterState, opts: &FormatOptions) {
+    // Parameter lists with syntax errors are kept as written
+    if node.has_error() {
+        state.write_node(node, source);
+        return;
+    }
+    let params: Vec<_> = node
+        .named_children(&mut node.walk())
+        .filter(|child| !is_comment(*child))
+        .collect();
 
-                    if needs_blank_line {
-                        state.write_blank_line();
-                    }
-                }
+    if params.is_empty() {
+        state.write_text("()");
+        state.mark_end(node.end_byte());
+        return;
+    }
 
-                format_node(child, source, state, opts);
-                prev_kind = Some(current_kind);
+    state.write_text("(");
+    state.increase_indent();
+    state.write_softline();
+    for (i, param) in params.iter().enumerate() {
+        if i > 0 {
+            state.write_text(",");
+            state.write_line();
+        }
+        // `name: Type` or `name: Type = default`
+        state.write_comments_before(param.start_byte());
+        for child in param.children(&mut param.walk()) {
+            match child.kind() {
+                ":" => state.write_text(": "),
+                "=" => state.write_text(" = "),
+                _ if is_type(child) => format_type(child, source, state),
+                _ => format_expression(child, source, state, opts),
             }
         }
+    }
+    state.decrease_indent();
+    state.write_softline();
+    state.write_text(")");
+    state.mark_end(node.end_byte());
+}
 
-        "block_comment" | "line_comment" => {
-            let text = node_text(node, source);
-            state.write_indent(opts);
-            state.write_text(&text);
+/// Format one expression of a block as a statement on its own line
+fn format_statement(node: Node, source: &[u8], state: &mut FormatterState, opts: &FormatOptions) {
+    match node.kind() {
+        ";" => {}
+        "assignment_expression"
+        | "call_expression"
+        | "block_comment"
+        | "line_comment"
+        | "ERROR" => format_node(node, source, state, opts),
+        "string" if is_docstring(node, source) => {
+            format_docstring(node, source, state, opts);
+            state.write_newline();
+        }
+        _ if is_control_flow(node) => format_node(node, source, state, opts),
+        _ => {
+            format_expression(node, source, state, opts);
             state.write_newline();
         }
+    }
+}
 
-        "use_statement" => {
-            state.write_indent(opts);
-            state.write_text("use ");
+/// Format an expression that is part of a line rather than a statement of its own
+fn format_expression(node: Node, source: &[u8], state: &mut FormatterState, opts: &FormatOptions) {
+    // Comments between the parts of an expression are written by position
+    if is_comment(node) {
+        return;
+    }
+    state.write_comments_before(node.start_byte());
 
-            // Find and format the string literal
+    match node.kind() {
+        "call_expression" => {
             for child in node.children(&mut node.walk()) {
-                if child.kind() == "string" {
-                    let target_text = node_text(child, source);
-                    state.write_text(&target_text);
-                    break;
+                match child.kind() {
+                    "arguments" => format_arguments(child, source, state, opts),
+                    _ => format_expression(child, source, state, opts),
                 }
             }
-            state.write_newline();
+        }
+        "binary_expression" => format_binary(node, source, state, opts),
+        "parenthesized_expression" | "unary_expression" => {
+            for child in node.children(&mut node.walk()) {
+                match child.kind() {
+                    // Keyword operators need a space before their operand
+                    "not" | "addressof" | "digestof" => {
+                        state.write_node(child, source);
+                        state.write_text(" ");
+                    }
+                    "-" | "-~" if is_binary_minus(node) => {
+                        state.write_node(child, source);
+                        state.write_text(" ");
+                    }
+                    _ => format_expression(child, source, state, opts),
+                }
+            }
+        }
+        "lambda_expression" => format_lambda(node, source, state, opts),
+        "recover_statement" => format_recover(node, source, state, opts),
+        "consume_statement" => {
+            // `consume x` or `consume ref x`
+            for (i, child) in node.children(&mut node.walk()).enumerate() {
+                if i > 0 {
+                    state.write_text(" ");
+                }
+                format_expression(child, source, state, opts);
+            }
+        }
+        "object_literal" => format_object_literal(node, source, state, opts),
+        "generic_expression" => {
+            for child in node.children(&mut node.walk()) {
+                format_expression(child, source, state, opts);
+            }
+        }
+        "type_args" => format_type(node, source, state),
+        "ERROR" => {
+            write_verbatim(node, node, source, state);
+        }
+        "member_expression" => {
+            // Format the receiver too, so chained calls get their own argument groups
+            for child in node.children(&mut node.walk()) {
+                format_expression(child, source, state, opts);
+            }
+        }
+        _ => state.write_node(node, source),
+    }
+}
+
+fn format_node(node: Node, source: &[u8], state: &mut FormatterState, opts: &FormatOptions) {
+    // Leading comments of this node
+    state.write_comments_before(node.start_byte());
+
+    match node.kind() {
+        "source_file" => {
+            // Handle the root of the file
+            let children: Vec<_> = node.children(&mut node.walk()).collect();
+            format_top_level(&children, source, state, opts);
+        }
+
+        "block_comment" | "line_comment" => {
+            // Comments are written through the trivia so each appears exactly once
+            state.write_comments_before(node.end_byte());
         }
 
-        "actor_definition" | "class_definition" => {
-            state.write_indent(opts);
+        "use_statement" => {
+            format_use(node, source, state);
+            state.write_newline();
+        }
 
-            // Handle actor/class keyword and name
+        "actor_definition"
+        | "class_definition"
+        | "primitive_definition"
+        | "trait_definition"
+        | "interface_definition"
+        | "struct_definition" => {
+            // Handle the keyword, capability, name, type parameters and provided types
+            let mut has_members = false;
             let mut cursor = node.walk();
             if cursor.goto_first_child() {
                 loop {
                     let child = cursor.node();
                     match child.kind() {
-                        "actor" | "class" => {
-                            state.write_text(&node_text(child, source));
-                            state.write_text(" ");
-                        }
-                        "capability" => {
-                            state.write_text(&node_text(child, source));
+                        "actor" | "class" | "primitive" | "trait" | "interface" | "struct"
+                        | "annotation" | "capability" => {
+                            state.write_node(child, source);
                             state.write_text(" ");
                         }
                         "identifier" => {
                             // This is the type name
-                            state.write_text(&node_text(child, source));
+                            state.write_node(child, source);
                         }
+                        "generic_parameters" => format_type(child, source, state),
                         "is" => {
                             state.write_text(" is ");
                         }
-                        "base_type" => {
-                            // This is the parent type name
-                            state.write_text(&node_text(child, source));
-                        }
-                        "members" => {
-                            // Now handle the body
+                        "string" => {
+                            // Docstring
                             state.write_newline();
                             state.increase_indent();
-                            format_node(child, source, state, opts);
+                            format_docstring(child, source, state, opts);
                             state.decrease_indent();
                         }
-                        _ => {}
-                    }
-                    if !cursor.goto_next_sibling() {
-                        break;
-                    }
-                }
-            }
-        }
-
-        "trait_definition" => {
-            state.write_indent(opts);
-
-            // Handle trait keyword and name
-            let mut cursor = node.walk();
-            if cursor.goto_first_child() {
-                loop {
-                    let child = cursor.node();
-                    match child.kind() {
-                        "trait" => {
-                            state.write_text(&node_text(child, source));
-                            state.write_text(" ");
-                        }
-                        "capability" => {
-                            state.write_text(&node_text(child, source));
-                            state.write_text(" ");
-                        }
-                        "identifier" => {
-                            state.write_text(&node_text(child, source));
-                        }
                         "members" => {
-                            state.write_newline();
+                            // Now handle the body
+                            if state.current_line_has_content {
+                                state.write_newline();
+                            }
                             state.increase_indent();
                             format_node(child, source, state, opts);
                             state.decrease_indent();
+                            has_members = true;
                         }
-                        _ => {}
-                    }
-                    if !cursor.goto_next_sibling() {
-                        break;
-                    }
-                }
-            }
-        }
-
-        "primitive_definition" => {
-            state.write_indent(opts);
-
-            // Handle primitive keyword and name
-            let mut cursor = node.walk();
-            if cursor.goto_first_child() {
-                loop {
-                    let child = cursor.node();
-                    match child.kind() {
-                        "primitive" => {
-                            state.write_text(&node_text(child, source));
-                            state.write_text(" ");
-                        }
-                        "identifier" => {
-                            state.write_text(&node_text(child, source));
+                        "ERROR" => format_verbatim(child, child, source, state),
+                        _ if !is_comment(child) => {
+                            // This is the provided type after `is`
+                            format_type(child, source, state);
                         }
                         _ => {}
                     }
@@ -310,11 +813,12 @@ fn format_node(node: Node, source: &[u8], state: &mut FormatterState, opts: &For
                     }
                 }
             }
-            state.write_newline();
+            if !has_members && state.current_line_has_content {
+                state.write_newline();
+            }
         }
 
         "type_definition" => {
-            state.write_indent(opts);
             state.write_text("type ");
 
             let mut cursor = node.walk();
@@ -322,7 +826,7 @@ fn format_node(node: Node, source: &[u8], state: &mut FormatterState, opts: &For
                 loop {
                     let child = cursor.node();
                     if child.kind() == "identifier" {
-                        state.write_text(&node_text(child, source));
+                        state.write_node(child, source);
                         break;
                     }
                     if !cursor.goto_next_sibling() {
@@ -341,7 +845,7 @@ fn format_node(node: Node, source: &[u8], state: &mut FormatterState, opts: &For
                         // Find the next significant node (the type union)
                         if cursor.goto_next_sibling() {
                             let union_node = cursor.node();
-                            state.write_text(&node_text(union_node, source));
+                            state.write_node(union_node, source);
                         }
                         break;
                     }
@@ -354,7 +858,6 @@ fn format_node(node: Node, source: &[u8], state: &mut FormatterState, opts: &For
         }
 
         "type_alias" => {
-            state.write_indent(opts);
             state.write_text("type ");
 
             let mut cursor = node.walk();
@@ -363,13 +866,17 @@ fn format_node(node: Node, source: &[u8], state: &mut FormatterState, opts: &For
                     let child = cursor.node();
                     match child.kind() {
                         "identifier" => {
-                            state.write_text(&node_text(child, source));
+                            state.write_node(child, source);
                         }
+                        "type_parameters" => format_type(child, source, state),
                         "is" => {
-                            state.write_text(" is ");
+                            state.write_text(" is");
                         }
-                        "union_type" => {
-                            state.write_text(&node_text(child, source));
+                        "union_type" => format_union_alias(child, source, state),
+                        _ if !is_comment(child) && child.kind() != "type" => {
+                            // The aliased type
+                            state.write_text(" ");
+                            format_type(child, source, state);
                         }
                         _ => {}
                     }
@@ -382,46 +889,41 @@ fn format_node(node: Node, source: &[u8], state: &mut FormatterState, opts: &For
         }
         "members" => {
             // Handle members of a type (fields, constructors, functions)
-            for child in node.children(&mut node.walk()) {
-                format_node(child, source, state, opts);
-            }
+            let children: Vec<_> = node.children(&mut node.walk()).collect();
+            format_members(&children, source, state, opts);
         }
 
         "field" => {
-            state.write_indent(opts);
-
             let mut cursor = node.walk();
             if cursor.goto_first_child() {
                 loop {
                     let child = cursor.node();
                     match child.kind() {
                         "let" | "var" | "embed" => {
-                            state.write_text(&node_text(child, source));
+                            state.write_node(child, source);
                             state.write_text(" ");
                         }
                         "identifier" => {
-                            state.write_text(&node_text(child, source));
+                            state.write_node(child, source);
                         }
                         ":" => {
                             state.write_text(": ");
                         }
-                        "base_type" => {
-                            state.write_text(&node_text(child, source));
-                        }
                         "=" => {
                             state.write_text(" = ");
                         }
+                        _ if is_type(child) => format_type(child, source, state),
                         _ => {
                             // For default values
-                            if child.kind() != "let"
+                            if !is_comment(child)
+                                && child.kind() != "let"
                                 && child.kind() != "var"
                                 && child.kind() != "embed"
                                 && child.kind() != "identifier"
                                 && child.kind() != ":"
                                 && child.kind() != "="
-                                && child.kind() != "base_type"
                             {
-                                state.write_text(&node_text(child, source));
+                                format_expression(child, source, state, opts);
                             }
                         }
                     }
@@ -434,19 +936,17 @@ fn format_node(node: Node, source: &[u8], state: &mut FormatterState, opts: &For
         }
 
         "field_definition" => {
-            state.write_indent(opts);
-
             let mut cursor = node.walk();
             if cursor.goto_first_child() {
                 loop {
                     let child = cursor.node();
                     match child.kind() {
                         "let" | "var" | "embed" => {
-                            state.write_text(&node_text(child, source));
+                            state.write_node(child, source);
                             state.write_text(" ");
                         }
                         "identifier" => {
-                            state.write_text(&node_text(child, source));
+                            state.write_node(child, source);
                         }
                         ":" => {
                             state.write_text(": ");
@@ -454,16 +954,18 @@ fn format_node(node: Node, source: &[u8], state: &mut FormatterState, opts: &For
                         "=" => {
                             state.write_text(" = ");
                         }
+                        _ if is_type(child) => format_type(child, source, state),
                         _ => {
-                            // For type annotations and default values
-                            if child.kind() != "let"
+                            // For default values
+                            if !is_comment(child)
+                                && child.kind() != "let"
                                 && child.kind() != "var"
                                 && child.kind() != "embed"
                                 && child.kind() != "identifier"
                                 && child.kind() != ":"
                                 && child.kind() != "="
                             {
-                                state.write_text(&node_text(child, source));
+                                format_expression(child, source, state, opts);
                             }
                         }
                     }
@@ -475,40 +977,84 @@ fn format_node(node: Node, source: &[u8], state: &mut FormatterState, opts: &For
             state.write_newline();
         }
         "method" => {
-            state.write_indent(opts);
-
+            let return_type = node.child_by_field_name("returns");
+            // The parameters and the return type are broken as one group, so
+            // the parameters break before the type arguments of the return type
+            let mut in_signature = false;
             let mut cursor = node.walk();
             if cursor.goto_first_child() {
                 loop {
                     let child = cursor.node();
+                    if in_signature && matches!(child.kind(), "ERROR" | "string" | "=>" | "block") {
+                        state.end_group();
+                        in_signature = false;
+                    }
                     match child.kind() {
-                        "fun" => {
-                            state.write_text(&node_text(child, source));
+                        "fun" | "annotation" | "capability" => {
+                            state.write_node(child, source);
                             state.write_text(" ");
                         }
-                        "identifier" => {
-                            state.write_text(&node_text(child, source));
+                        "identifier" | "@" => {
+                            state.write_node(child, source);
                         }
+                        "generic_parameters" => format_type(child, source, state),
                         "parameters" => {
-                            state.write_text(&node_text(child, source));
+                            state.begin_group();
+                            in_signature = true;
+                            format_parameters(child, source, state, opts);
                         }
                         ":" => {
                             state.write_text(": ");
                         }
-                        "base_type" => {
-                            state.write_text(&node_text(child, source));
+                        "?" => {
+                            state.write_text(" ?");
+                        }
+                        _ if Some(child) == return_type => {
+                            format_type(child, source, state);
+                        }
+                        "ERROR" => {
+                            state.write_text(" ");
+                            state.write_node(child, source);
+                        }
+                        "string" => {
+                            // Docstring of a method without a body
+                            state.write_newline();
+                            state.increase_indent();
+                            format_docstring(child, source, state, opts);
+                            state.write_newline();
+                            state.decrease_indent();
                         }
                         "=>" => {
                             state.write_text(" =>");
                         }
                         "block" => {
-                            // For simple single-expression blocks, keep on same line
-                            let block_text = node_text(child, source);
-                            let trimmed = block_text.trim();
-                            if trimmed.lines().count() == 1 && trimmed.len() < 50 {
-                                // Simple one-liner, keep on same line
-                                state.write_text(" ");
-                                state.write_text(trimmed);
+                            // A single expression, or expressions side by
+                            // side, go after the `=>` if they fit. Line breaks
+                            // the formatter may remove are not considered, so
+                            // that a second pass makes the same choice.
+                            let expressions: Vec<_> = child
+                                .named_children(&mut child.walk())
+                                .filter(|expression| !is_comment(*expression))
+                                .collect();
+                            let one_line = match expressions[..] {
+                                [expression] => {
+                                    (expression.kind() != "assignment_expression"
+                                        && !is_control_flow(expression))
+                                        || !node_text(child, source).trim().contains('\n')
+                                }
+                                // Expressions side by side, as for `i - 1`
+                                _ => expressions.windows(2).all(|pair| {
+                                    pair[0].end_position().row == pair[1].start_position().row
+                                }),
+                            };
+                            if one_line {
+                                // Simple one-liner, keep on same line if it fits
+                                state.begin_group();
+                                state.increase_indent();
+                                state.write_line();
+                                format_inline_block(child, source, state, opts);
+                                state.decrease_indent();
+                                state.end_group();
                             } else {
                                 // Multi-line or complex block, indent
                                 state.write_newline();
@@ -524,19 +1070,23 @@ fn format_node(node: Node, source: &[u8], state: &mut FormatterState, opts: &For
                     }
                 }
             }
-            state.write_newline();
+            if in_signature {
+                state.end_group();
+            }
+            // A multi-line body already ends the line
+            if state.current_line_has_content {
+                state.write_newline();
+            }
         }
 
-        "constructor" | "function_definition" => {
-            state.write_indent(opts);
-
+        "constructor" | "behavior" | "function_definition" => {
             let mut cursor = node.walk();
             if cursor.goto_first_child() {
                 loop {
                     let child = cursor.node();
                     match child.kind() {
                         "new" | "fun" | "be" => {
-                            state.write_text(&node_text(child, source));
+                            state.write_node(child, source);
                             state.write_text(" ");
                         }
                         "val" | "ref" | "iso" | "trn" | "box" | "tag" => {
@@ -544,20 +1094,38 @@ fn format_node(node: Node, source: &[u8], state: &mut FormatterState, opts: &For
                         }
                         "capability" => {
                             // This handles val, ref, iso, trn, box, tag
-                            state.write_text(&node_text(child, source));
+                            state.write_node(child, source);
                             state.write_text(" ");
                         }
                         "identifier" => {
-                            state.write_text(&node_text(child, source));
+                            state.write_node(child, source);
                         }
+                        "generic_parameters" => format_type(child, source, state),
                         "parameters" => {
-                            state.write_text(&node_text(child, source));
+                            state.begin_group();
+                            format_parameters(child, source, state, opts);
+                            state.end_group();
                         }
                         ":" => {
                             state.write_text(": ");
                         }
-                        "=>" => {
-                            state.write_text(" =>");
+                        "?" => {
+                            state.write_text(" ?");
+                        }
+                        "ERROR" => {
+                            state.write_text(" ");
+                            state.write_node(child, source);
+                        }
+                        "string" => {
+                            // Docstring of a method without a body
+                            state.write_newline();
+                            state.increase_indent();
+                            format_docstring(child, source, state, opts);
+                            state.write_newline();
+                            state.decrease_indent();
+                        }
+                        "=>" => {
+                            state.write_text(" =>");
                         }
                         "block" => {
                             // Format the method body - always put it on new line and indent
@@ -568,7 +1136,8 @@ fn format_node(node: Node, source: &[u8], state: &mut FormatterState, opts: &For
                         }
                         _ => {
                             // Handle return type annotations
-                            if child.kind() != "new"
+                            if !is_comment(child)
+                                && child.kind() != "new"
                                 && child.kind() != "fun"
                                 && child.kind() != "be"
                                 && child.kind() != "identifier"
@@ -577,6 +1146,7 @@ fn format_node(node: Node, source: &[u8], state: &mut FormatterState, opts: &For
                                 && child.kind() != "=>"
                                 && child.kind() != "block"
                                 && child.kind() != "capability"
+                                && child.kind() != "?"
                                 && child.kind() != "val"
                                 && child.kind() != "ref"
                                 && child.kind() != "iso"
@@ -584,7 +1154,7 @@ fn format_node(node: Node, source: &[u8], state: &mut FormatterState, opts: &For
                                 && child.kind() != "box"
                                 && child.kind() != "tag"
                             {
-                                state.write_text(&node_text(child, source));
+                                format_type(child, source, state);
                             }
                         }
                     }
@@ -595,32 +1165,133 @@ fn format_node(node: Node, source: &[u8], state: &mut FormatterState, opts: &For
             }
         }
 
-        "if_statement" => {
-            state.write_indent(opts);
+        "if_statement" | "iftype_statement" => format_conditional(node, source, state, opts),
 
-            let mut cursor = node.walk();
-            if cursor.goto_first_child() {
-                loop {
-                    let child = cursor.node();
-                    match child.kind() {
-                        "if_block" => {
-                            // Handle "if condition"
-                            format_if_block(child, source, state, opts);
+        "match_statement" => format_match(node, source, state, opts),
+
+        "while_statement" => {
+            for child in node.children(&mut node.walk()) {
+                match child.kind() {
+                    "while" => {
+                        state.write_node(child, source);
+                        state.write_text(" ");
+                    }
+                    "do_block" => format_do_block(child, source, state, opts),
+                    _ if !is_comment(child) => {
+                        // The condition
+                        format_expression(child, source, state, opts);
+                    }
+                    _ => {}
+                }
+            }
+        }
+
+        "for_statement" => {
+            for child in node.children(&mut node.walk()) {
+                match child.kind() {
+                    "for" => {
+                        state.write_node(child, source);
+                        state.write_text(" ");
+                    }
+                    "in" => {
+                        state.write_text(" ");
+                        state.write_node(child, source);
+                        state.write_text(" ");
+                    }
+                    "do_block" => format_do_block(child, source, state, opts),
+                    _ if !is_comment(child) => {
+                        // The loop variables and the iterator
+                        format_expression(child, source, state, opts);
+                    }
+                    _ => {}
+                }
+            }
+        }
+
+        "repeat_statement" => {
+            for child in node.children(&mut node.walk()) {
+                match child.kind() {
+                    "repeat" => {
+                        state.write_node(child, source);
+                        state.write_newline();
+                    }
+                    "block" => {
+                        // The body
+                        state.increase_indent();
+                        format_node(child, source, state, opts);
+                        state.decrease_indent();
+                    }
+                    "until" => {
+                        if state.current_line_has_content {
+                            state.write_newline();
                         }
-                        "then_block" => {
-                            // Handle "then body"
-                            format_then_block(child, source, state, opts);
+                        state.write_node(child, source);
+                        state.write_text(" ");
+                    }
+                    "else_block" => format_else_block(child, source, state, opts),
+                    "end" => {
+                        // `until condition end` unless an `else` came in between
+                        if state.current_line_has_content {
+                            state.write_text(" ");
                         }
-                        "end" => {
-                            state.write_indent(opts);
-                            state.write_text("end");
-                            state.write_newline();
+                        state.write_node(child, source);
+                        state.write_newline();
+                    }
+                    _ if !is_comment(child) => {
+                        // The condition
+                        format_expression(child, source, state, opts);
+                    }
+                    _ => {}
+                }
+            }
+        }
+
+        "with_statement" => {
+            for child in node.children(&mut node.walk()) {
+                match child.kind() {
+                    "with" => {
+                        state.write_node(child, source);
+                        state.write_text(" ");
+                    }
+                    "," => state.write_text(", "),
+                    "with_elem" => {
+                        // `name = resource`
+                        for part in child.children(&mut child.walk()) {
+                            match part.kind() {
+                                "=" => state.write_text(" = "),
+                                "block" => format_inline_block(part, source, state, opts),
+                                _ => format_expression(part, source, state, opts),
+                            }
                         }
-                        _ => {}
                     }
-                    if !cursor.goto_next_sibling() {
-                        break;
+                    "do_block" => format_do_block(child, source, state, opts),
+                    _ => {}
+                }
+            }
+        }
+
+        "try_statement" => {
+            for child in node.children(&mut node.walk()) {
+                match child.kind() {
+                    "try" => {
+                        state.write_node(child, source);
+                        state.write_newline();
+                    }
+                    "block" => {
+                        // The body
+                        state.increase_indent();
+                        format_node(child, source, state, opts);
+                        state.decrease_indent();
                     }
+                    "else_block" | "then_block" => format_else_block(child, source, state, opts),
+                    "end" => {
+                        if state.current_line_has_content {
+                            state.write_newline();
+                        }
+                        state.write_node(child, source);
+                        state.write_newline();
+                    }
+                    _ => {}
                 }
             }
         }
@@ -629,14 +1300,15 @@ fn format_node(node: Node, source: &[u8], state: &mut FormatterState, opts: &For
             // Check if this is a block of simple assignments that should be on one line
             let children: Vec<_> = node.children(&mut node.walk()).collect();
 
-            // Check if this block contains only assignment_expression and ; nodes
+            // Check if this block contains only assignment_expression and ; nodes,
+            // with at least one `;` joining them on a line
             let is_simple_assignments = children
                 .iter()
-                .all(|child| matches!(child.kind(), "assignment_expression" | ";"));
+                .all(|child| matches!(child.kind(), "assignment_expression" | ";"))
+                && children.iter().any(|child| child.kind() == ";");
 
             if is_simple_assignments && children.len() > 2 {
                 // Format multiple assignments on one line
-                state.write_indent(opts);
                 let mut first = true;
                 for child in children {
                     match child.kind() {
@@ -644,7 +1316,7 @@ fn format_node(node: Node, source: &[u8], state: &mut FormatterState, opts: &For
                             if !first {
                                 state.write_text(" ");
                             }
-                            state.write_text(&node_text(child, source));
+                            state.write_node(child, source);
                             first = false;
                         }
                         ";" => {
@@ -655,10 +1327,8 @@ fn format_node(node: Node, source: &[u8], state: &mut FormatterState, opts: &For
                 }
                 state.write_newline();
             } else {
-                // Handle general blocks normally
-                for child in children {
-                    format_node(child, source, state, opts);
-                }
+                // Handle general blocks one statement per line
+                format_statements(&children, source, state, opts);
             }
         }
 
@@ -670,31 +1340,7 @@ fn format_node(node: Node, source: &[u8], state: &mut FormatterState, opts: &For
                 || (parent.is_some_and(|p| p.kind() == "block")
                     && grandparent.is_some_and(|gp| !matches!(gp.kind(), "assignment_expression")));
 
-            if is_standalone {
-                state.write_indent(opts);
-            }
-
-            // Format function calls more intelligently
-            for child in node.children(&mut node.walk()) {
-                match child.kind() {
-                    "member_expression" => {
-                        // This is the function name (e.g., EmailMessage.create)
-                        state.write_text(&node_text(child, source));
-                    }
-                    "identifier" => {
-                        // This is a simple function call (no member access)
-                        state.write_text(&node_text(child, source));
-                    }
-                    "arguments" => {
-                        // Format arguments, keeping simple calls on one line
-                        format_arguments(child, source, state, opts);
-                    }
-                    _ => {
-                        // Handle other components as needed
-                        format_node(child, source, state, opts);
-                    }
-                }
-            }
+            format_expression(node, source, state, opts);
 
             if is_standalone {
                 state.write_newline();
@@ -702,14 +1348,13 @@ fn format_node(node: Node, source: &[u8], state: &mut FormatterState, opts: &For
         }
 
         "assignment_expression" => {
-            state.write_indent(opts);
             // Format children individually instead of preserving original formatting
             let mut first = true;
             for child in node.children(&mut node.walk()) {
                 match child.kind() {
                     "variable_declaration" => {
                         // Handle let variable declarations
-                        state.write_text(&node_text(child, source));
+                        state.write_node(child, source);
                         first = false;
                     }
                     "identifier" => {
@@ -717,7 +1362,7 @@ fn format_node(node: Node, source: &[u8], state: &mut FormatterState, opts: &For
                         if !first {
                             state.write_text(" ");
                         }
-                        state.write_text(&node_text(child, source));
+                        state.write_node(child, source);
                         first = false;
                     }
                     "=" => {
@@ -734,23 +1379,33 @@ fn format_node(node: Node, source: &[u8], state: &mut FormatterState, opts: &For
                             )
                         {
                             // Simple value, format directly
-                            state.write_text(&node_text(block_children[0], source));
+                            state.write_node(block_children[0], source);
+                        } else if block_children.iter().any(|c| is_control_flow(*c)) {
+                            // Control flow values have their own layout
+                            for block_child in block_children {
+                                if is_control_flow(block_child) {
+                                    format_node(block_child, source, state, opts);
+                                } else {
+                                    format_expression(block_child, source, state, opts);
+                                }
+                            }
                         } else {
                             // Complex expression (like function calls), format normally
                             // Don't add extra indentation since we're already in an assignment
-                            for block_child in block_children {
-                                format_node(block_child, source, state, opts);
-                            }
+                            format_sequence(child, source, state, opts);
                         }
                         first = false;
                     }
                     _ => {
-                        format_node(child, source, state, opts);
+                        format_expression(child, source, state, opts);
                         first = false;
                     }
                 }
             }
-            state.write_newline();
+            // Values with their own layout (like if blocks) already end the line
+            if state.current_line_has_content {
+                state.write_newline();
+            }
         }
 
         "variable_declaration" => {
@@ -758,8 +1413,7 @@ fn format_node(node: Node, source: &[u8], state: &mut FormatterState, opts: &For
         }
 
         "assignment" => {
-            state.write_indent(opts);
-            state.write_text(&node_text(node, source));
+            state.write_node(node, source);
             state.write_newline();
         }
 
@@ -770,107 +1424,1236 @@ fn format_node(node: Node, source: &[u8], state: &mut FormatterState, opts: &For
             // These are handled by their parent nodes
         }
 
-        "ERROR" => {
-            // Handle ERROR nodes by processing their children with basic formatting
-            let text = node_text(node, source);
-
-            // Check if this looks like the start of an if statement
-            if text.starts_with("if ") && text.contains("then") {
-                // Format as if statement start
-                state.write_indent(opts);
-                let parts: Vec<&str> = text.splitn(2, "then").collect();
-                if parts.len() == 2 {
-                    state.write_text(&format!("{} then", parts[0].trim()));
+        "ERROR" => format_verbatim(node, node, source, state),
+
+        "string" => {
+            // Handle string literals
+            state.write_node(node, source);
+        }
+
+        // Nodes without formatting rules are kept as written
+        _ => format_verbatim(node, node, source, state),
+    }
+}
+
+/// Write the nodes from `first` to `last` as they appear in the source, on
+/// lines of their own
+///
+/// This is used for nodes the formatter cannot handle and for code kept as
+/// written by a directive comment. Only the first line is re-indented; the
+/// rest, including any comments inside, is kept byte-for-byte.
+fn format_verbatim(first: Node, last: Node, source: &[u8], state: &mut FormatterState) {
+    let end = write_verbatim(first, last, source, state);
+    let next_on_same_line = last
+        .next_sibling()
+        .is_some_and(|next| !source[end..next.start_byte()].contains(&b'\n'));
+    if !next_on_same_line {
+        state.write_newline();
+    }
+}
+
+/// Write the nodes from `first` to `last` as they appear in the source,
+/// separated from the code before them as in the source, and return the end
+/// of the text written
+fn write_verbatim(first: Node, last: Node, source: &[u8], state: &mut FormatterState) -> usize {
+    // Code that shared a line with these nodes keeps sharing it
+    let gap = &source[state.last_end.min(first.start_byte())..first.start_byte()];
+    if gap.contains(&b'\n') {
+        if state.current_line_has_content {
+            state.write_newline();
+        }
+    } else if state.current_line_has_content || state.reopen_line() {
+        state.write_text(&String::from_utf8_lossy(gap));
+    }
+    // Error nodes can end in the line break after them, which the caller
+    // writes like any other
+    let mut end = last.end_byte();
+    while end > first.start_byte() && source[end - 1].is_ascii_whitespace() {
+        end -= 1;
+    }
+    state.write_source(first.start_byte(), end, source);
+    state
+        .trivia
+        .consume_range(first.start_byte(), last.end_byte());
+    end
+}
+
+/// A node of a declaration, member or statement list, or a run of them that
+/// directive comments keep as written
+#[derive(Clone, Copy)]
+enum Unit<'tree> {
+    Node(Node<'tree>),
+    Verbatim {
+        first: Node<'tree>,
+        last: Node<'tree>,
+    },
+}
+
+impl<'tree> Unit<'tree> {
+    fn first(self) -> Node<'tree> {
+        match self {
+            Unit::Node(node) | Unit::Verbatim { first: node, .. } => node,
+        }
+    }
+
+    fn last(self) -> Node<'tree> {
+        match self {
+            Unit::Node(node) | Unit::Verbatim { last: node, .. } => node,
+        }
+    }
+}
+
+/// Group `children` into units, merging the nodes inside a
+/// `// ponyfmt: off` region and marking the nodes after `// ponyfmt: skip`
+fn units<'tree>(children: &[Node<'tree>], trivia: &Trivia) -> Vec<Unit<'tree>> {
+    let mut units: Vec<Unit> = Vec::new();
+    for child in children.iter().copied() {
+        if let Some(Unit::Verbatim { last, .. }) = units.last_mut()
+            && trivia.is_off(child.start_byte())
+        {
+            *last = child;
+        } else if !is_comment(child)
+            && (trivia.is_off(child.start_byte())
+                || trivia.is_skipped(preceding_code_end(child), child.start_byte()))
+        {
+            units.push(Unit::Verbatim {
+                first: child,
+                last: child,
+            });
+        } else {
+            units.push(Unit::Node(child));
+        }
+    }
+    units
+}
+
+/// End of the code before `node`, skipping comments and climbing out of
+/// parents that start with `node`
+fn preceding_code_end(node: Node) -> usize {
+    let mut current = node;
+    loop {
+        let mut prev = current.prev_sibling();
+        while let Some(sibling) = prev
+            && is_comment(sibling)
+        {
+            prev = sibling.prev_sibling();
+        }
+        if let Some(prev) = prev {
+            return prev.end_byte();
+        }
+        match current.parent() {
+            Some(parent) => current = parent,
+            None => return 0,
+        }
+    }
+}
+
+/// Format a unit of a declaration, member or statement list
+fn format_unit(
+    unit: Unit,
+    source: &[u8],
+    state: &mut FormatterState,
+    opts: &FormatOptions,
+    format: fn(Node, &[u8], &mut FormatterState, &FormatOptions),
+) {
+    match unit {
+        Unit::Node(node) => format(node, source, state, opts),
+        Unit::Verbatim { first, last } => format_verbatim(first, last, source, state),
+    }
+}
+
+/// Format top-level declarations, separating them with blank lines by kind
+fn format_top_level(
+    children: &[Node],
+    source: &[u8],
+    state: &mut FormatterState,
+    opts: &FormatOptions,
+) {
+    let mut prev_kind: Option<&str> = None;
+    let mut prev_end_row = None;
+    // End of the run of `use` statements written sorted most recently
+    let mut sorted_until = 0;
+
+    for unit in units(children, &state.trivia) {
+        let child = unit.first();
+        let current_kind = child.kind();
+
+        // Comments already written next to the code they belong to
+        if child.is_extra() && state.trivia.is_emitted(child.start_byte()) {
+            continue;
+        }
+        if child.start_byte() < sorted_until {
+            continue;
+        }
+
+        // Add blank lines between different types of top-level declarations
+        if let Some(prev) = prev_kind {
+            let needs_blank_line = match (prev, current_kind) {
+                // Always add blank line after block comments (unless next is also a comment)
+                ("block_comment", kind) if kind != "block_comment" && kind != "line_comment" => {
+                    true
+                }
+                // Add blank lines between different declaration types
+                ("primitive_definition", kind)
+                    if kind != "primitive_definition" && kind != "line_comment" =>
+                {
+                    true
+                }
+                ("type_alias", kind) if kind != "type_alias" && kind != "line_comment" => true,
+                ("trait_definition" | "interface_definition", kind)
+                    if kind != prev && kind != "line_comment" =>
+                {
+                    true
+                }
+                ("class_definition" | "actor_definition" | "struct_definition", kind)
+                    if kind != "line_comment" =>
+                {
+                    true
+                }
+                // Add blank line before line comments that come after class definitions
+                ("class_definition", "line_comment")
+                | ("trait_definition", "line_comment")
+                | ("interface_definition", "line_comment")
+                | ("primitive_definition", "line_comment") => true,
+                // Add blank line before first declaration after use statements
+                ("use_statement", kind) if kind != "use_statement" && kind != "line_comment" => {
+                    true
+                }
+                _ => false,
+            };
+
+            // A syntax error continuing the line of the code before it stays there
+            let continues_line =
+                current_kind == "ERROR" && prev_end_row == Some(child.start_position().row);
+            if needs_blank_line && !continues_line {
+                state.write_blank_line();
+            }
+        }
+
+        if opts.sort_uses && current_kind == "use_statement" && matches!(unit, Unit::Node(_)) {
+            let run = use_run(children, child, &state.trivia);
+            format_sorted_uses(&run, source, state);
+            sorted_until = run[run.len() - 1].end_byte();
+            prev_kind = Some(current_kind);
+            prev_end_row = Some(run[run.len() - 1].end_position().row);
+            continue;
+        }
+
+        format_unit(unit, source, state, opts, format_node);
+        prev_kind = Some(unit.last().kind());
+        prev_end_row = Some(unit.last().end_position().row);
+    }
+}
+
+/// The run of adjacent `use` statements in `children` starting at `first`,
+/// with the comments trailing them on their lines
+///
+/// A comment on a line of its own ends the run, so such comments never move
+/// away from the statement they describe.
+fn use_run<'tree>(
+    children: &[Node<'tree>],
+    first: Node<'tree>,
+    trivia: &Trivia,
+) -> Vec<Node<'tree>> {
+    let mut run: Vec<Node> = Vec::new();
+    for child in children.iter().copied().skip_while(|child| *child != first) {
+        let trailing = is_comment(child)
+            && run
+                .last()
+                .is_some_and(|prev| prev.end_position().row == child.start_position().row);
+        let sortable = child.kind() == "use_statement" && !trivia.is_off(child.start_byte());
+        if !trailing && !sortable {
+            break;
+        }
+        run.push(child);
+    }
+    run
+}
+
+/// Write a run of `use` statements sorted by their source text, each with
+/// the comments trailing it in the source
+fn format_sorted_uses(run: &[Node], source: &[u8], state: &mut FormatterState) {
+    // Claim the trailing comments first, so that no statement written
+    // earlier takes those of a statement that followed it in the source
+    let mut uses: Vec<_> = run
+        .iter()
+        .filter(|node| node.kind() == "use_statement")
+        .map(|&node| {
+            let trailing = state.trivia.take_trailing(node.end_byte());
+            (node_text(node, source), node, trailing)
+        })
+        .collect();
+    uses.sort_by(|a, b| a.0.cmp(&b.0));
+    for (_, node, trailing) in uses {
+        state.write_comments_before(node.start_byte());
+        format_use(node, source, state);
+        for comment in trailing {
+            state.push(Doc::LineSuffix(format!(" {}", comment.text)));
+        }
+        state.write_newline();
+    }
+}
+
+/// Format a `use` statement without ending the line
+fn format_use(node: Node, source: &[u8], state: &mut FormatterState) {
+    let mut cursor = node.walk();
+    let mut parts = node
+        .named_children(&mut cursor)
+        .filter(|child| !is_comment(*child));
+    let package = match (parts.next(), parts.next()) {
+        (Some(child), None) if child.kind() == "string" => child,
+        // Aliases, guards and FFI declarations are kept as written
+        _ => return state.write_node(node, source),
+    };
+    state.write_text("use ");
+    state.write_node(package, source);
+}
+
+/// Format the members of a type, one after another
+fn format_members(
+    children: &[Node],
+    source: &[u8],
+    state: &mut FormatterState,
+    opts: &FormatOptions,
+) {
+    let mut prev = None;
+    for unit in units(children, &state.trivia) {
+        let child = unit.first();
+        if child.is_extra() && state.trivia.is_emitted(child.start_byte()) {
+            continue;
+        }
+        state.write_preserved_blank_lines(prev, child, source, opts);
+        format_unit(unit, source, state, opts, format_node);
+        prev = Some(unit.last());
+    }
+}
+
+/// Format the statements of a block, one per line
+///
+/// Nodes the grammar splits a statement into, like `return` and its value,
+/// and statements joined by `;` stay on the line they share in the source.
+fn format_statements(
+    children: &[Node],
+    source: &[u8],
+    state: &mut FormatterState,
+    opts: &FormatOptions,
+) {
+    let mut prev: Option<Node> = None;
+    for unit in units(children, &state.trivia) {
+        let child = unit.first();
+        if child.is_extra() && state.trivia.is_emitted(child.start_byte()) {
+            continue;
+        }
+        if child.kind() == ";" {
+            state.reopen_line();
+            state.write_node(child, source);
+            state.write_newline();
+            prev = Some(child);
+            continue;
+        }
+        let same_line = prev.is_some_and(|prev| {
+            prev.end_position().row == child.start_position().row
+                && !is_comment(prev)
+                && !is_comment(child)
+        });
+        // Verbatim units keep the source between them and the code before
+        if same_line && matches!(unit, Unit::Node(_)) && child.kind() != "ERROR" {
+            if state.current_line_has_content || state.reopen_line() {
+                state.write_text(" ");
+            }
+        } else {
+            state.write_preserved_blank_lines(prev, child, source, opts);
+        }
+        format_unit(unit, source, state, opts, format_statement);
+        prev = Some(unit.last());
+    }
+}
+
+/// Format a `match`, with the cases and `else` aligned with the `match`
+/// keyword and their bodies indented below them
+fn format_match(node: Node, source: &[u8], state: &mut FormatterState, opts: &FormatOptions) {
+    for child in node.children(&mut node.walk()) {
+        match child.kind() {
+            "match" => state.write_node(child, source),
+            "block" => {
+                // The matched expression
+                state.write_text(" ");
+                format_inline_block(child, source, state, opts);
+            }
+            "case_statement" => {
+                state.write_newline();
+                format_case(child, source, state, opts);
+            }
+            "else_block" => format_else_block(child, source, state, opts),
+            "end" => {
+                if state.current_line_has_content {
                     state.write_newline();
-                    state.increase_indent();
-                    // Format the rest as body content
-                    if !parts[1].trim().is_empty() {
-                        state.write_indent(opts);
-                        state.write_text(parts[1].trim());
-                    }
-                }
-            } else if text.trim() == "end" || text.ends_with("end") {
-                // Format as if statement end
-                if text.trim() != "end" {
-                    // There's content before "end" (like ")end")
-                    let content = text.trim_end_matches("end").trim();
-                    if !content.is_empty() {
-                        state.write_text(content);
-                        state.write_newline();
+                }
+                state.write_node(child, source);
+                state.write_newline();
+            }
+            _ => {}
+        }
+    }
+}
+
+/// Format one `| pattern if guard => body` case of a `match`
+///
+/// A body written on one line stays after the `=>` if it fits; longer bodies
+/// go on the following lines, one indent deeper.
+fn format_case(node: Node, source: &[u8], state: &mut FormatterState, opts: &FormatOptions) {
+    for child in node.children(&mut node.walk()) {
+        match child.kind() {
+            "|" => {
+                state.write_node(child, source);
+                state.write_text(" ");
+            }
+            "if_block" => {
+                // The guard
+                for part in child.children(&mut child.walk()) {
+                    match part.kind() {
+                        "if" => {
+                            state.write_text(" ");
+                            state.write_node(part, source);
+                            state.write_text(" ");
+                        }
+                        "block" => format_inline_block(part, source, state, opts),
+                        _ => {}
                     }
                 }
+            }
+            "=>" => {
+                state.write_text(" ");
+                state.write_node(child, source);
+            }
+            "block" => {
+                let statements: Vec<_> = child
+                    .named_children(&mut child.walk())
+                    .filter(|statement| !is_comment(*statement))
+                    .collect();
+                if statements.len() == 1 && !node_text(child, source).contains('\n') {
+                    state.begin_group();
+                    state.increase_indent();
+                    state.write_line();
+                    format_expression(statements[0], source, state, opts);
+                    state.decrease_indent();
+                    state.end_group();
+                } else {
+                    state.write_newline();
+                    state.increase_indent();
+                    format_node(child, source, state, opts);
+                    state.decrease_indent();
+                }
+            }
+            _ if !is_comment(child) => {
+                // The pattern
+                format_expression(child, source, state, opts);
+            }
+            _ => {}
+        }
+    }
+}
+
+/// Format a block that is part of a line, like a condition
+fn format_inline_block(
+    node: Node,
+    source: &[u8],
+    state: &mut FormatterState,
+    opts: &FormatOptions,
+) {
+    let expressions: Vec<_> = node
+        .named_children(&mut node.walk())
+        .filter(|child| !is_comment(*child))
+        .collect();
+    match expressions[..] {
+        [expression] => format_expression(expression, source, state, opts),
+        _ => format_sequence(node, source, state, opts),
+    }
+}
+
+/// Format the expressions of a block that sit side by side on one line
+///
+/// The grammar reads `i - 1` inside a block as `i` followed by the unary
+/// expression `-1`; such a minus is written as the binary operator it is.
+/// Blocks spanning several lines or using `;` are kept as written, since a
+/// `-` starting a line is a unary minus in Pony.
+fn format_sequence(node: Node, source: &[u8], state: &mut FormatterState, opts: &FormatOptions) {
+    let children: Vec<_> = node
+        .children(&mut node.walk())
+        .filter(|child| !is_comment(*child))
+        .collect();
+    let one_line = children
+        .windows(2)
+        .all(|pair| pair[0].end_position().row == pair[1].start_position().row);
+    if !one_line || children.iter().any(|child| child.kind() == ";") {
+        state.write_node(node, source);
+        return;
+    }
+    for (i, child) in children.iter().enumerate() {
+        if i > 0 {
+            state.write_text(" ");
+        }
+        format_expression(*child, source, state, opts);
+    }
+}
+
+/// Whether the unary minus `node` is really the binary minus of `i - 1`,
+/// i.e. it starts an expression that follows another on the same line of
+/// a block
+fn is_binary_minus(node: Node) -> bool {
+    let mut expression = node;
+    while let Some(parent) = expression.parent()
+        && parent.kind() == "binary_expression"
+        && parent.child(0) == Some(expression)
+    {
+        expression = parent;
+    }
+    expression.parent().is_some_and(|p| p.kind() == "block")
+        && expression.prev_named_sibling().is_some_and(|prev| {
+            !is_comment(prev) && prev.end_position().row == node.start_position().row
+        })
+}
+
+/// Format a chain of binary operations on one line if it fits, otherwise
+/// with a line break before each operator and the continuation lines
+/// indented one level
+///
+/// Pony has no operator precedence, so parentheses are always kept as
+/// written. A line is never broken before `-`, which would turn it into a
+/// unary minus. A chain in which the source already has a `-` starting a
+/// line is kept as written: Pony reads that `-` as the start of a new
+/// expression, so joining the lines would change the program.
+fn format_binary(node: Node, source: &[u8], state: &mut FormatterState, opts: &FormatOptions) {
+    let mut operands = Vec::new();
+    let mut operators = Vec::new();
+    flatten_binary(node, &mut operands, &mut operators);
+
+    if operators.iter().any(|operator| {
+        matches!(operator.kind(), "-" | "-~") && starts_line(source, operator.start_byte())
+    }) {
+        state.write_node(node, source);
+        return;
+    }
+
+    state.begin_group();
+    format_expression(operands[0], source, state, opts);
+    state.increase_indent();
+    for (operator, operand) in operators.iter().zip(&operands[1..]) {
+        if matches!(operator.kind(), "-" | "-~") {
+            state.write_text(" ");
+        } else {
+            state.write_line();
+        }
+        state.write_node(*operator, source);
+        state.write_text(" ");
+        format_expression(*operand, source, state, opts);
+    }
+    state.decrease_indent();
+    state.end_group();
+}
+
+/// Collect the operands and operators of a left-nested chain like
+/// `a + b + c`, which the grammar reads as `(a + b) + c`
+fn flatten_binary<'tree>(
+    node: Node<'tree>,
+    operands: &mut Vec<Node<'tree>>,
+    operators: &mut Vec<Node<'tree>>,
+) {
+    let mut cursor = node.walk();
+    let children: Vec<_> = node
+        .children(&mut cursor)
+        .filter(|child| !is_comment(*child))
+        .collect();
+    for child in children {
+        if child.kind() == "binary_expression" && operands.is_empty() && operators.is_empty() {
+            flatten_binary(child, operands, operators);
+        } else if child.is_named() {
+            operands.push(child);
+        } else {
+            operators.push(child);
+        }
+    }
+}
+
+/// Format the `do ... else ... end` part of a `while` or `for` loop
+fn format_do_block(node: Node, source: &[u8], state: &mut FormatterState, opts: &FormatOptions) {
+    for child in node.children(&mut node.walk()) {
+        match child.kind() {
+            "do" => {
+                state.write_text(" ");
+                state.write_node(child, source);
+                state.write_newline();
+            }
+            "block" => {
+                // The loop body
+                state.increase_indent();
+                format_node(child, source, state, opts);
                 state.decrease_indent();
-                state.write_indent(opts);
-                state.write_text("end");
+            }
+            "else_block" => format_else_block(child, source, state, opts),
+            "end" => {
+                if state.current_line_has_content {
+                    state.write_newline();
+                }
+                state.write_node(child, source);
                 state.write_newline();
-            } else {
-                // For other ERROR nodes, just format the content with indentation
-                state.write_indent(opts);
-                state.write_text(&text);
+            }
+            _ => {}
+        }
+    }
+}
+
+/// Format an `else` clause, or the `then` clause of a `try`, with its body
+/// indented below the keyword
+fn format_else_block(node: Node, source: &[u8], state: &mut FormatterState, opts: &FormatOptions) {
+    if state.current_line_has_content {
+        state.write_newline();
+    }
+    for child in node.children(&mut node.walk()) {
+        match child.kind() {
+            "else" | "then" => {
+                state.write_node(child, source);
                 state.write_newline();
             }
+            "block" => {
+                state.increase_indent();
+                format_node(child, source, state, opts);
+                state.decrease_indent();
+            }
+            _ => {}
         }
+    }
+}
 
-        "string" => {
-            // Handle string literals
-            state.write_text(&node_text(node, source));
+/// Format `if`, `ifdef` and `iftype` with their `elseif` and `else`
+/// branches, every body indented one level below the keyword of its branch
+fn format_conditional(node: Node, source: &[u8], state: &mut FormatterState, opts: &FormatOptions) {
+    for child in node.children(&mut node.walk()) {
+        match child.kind() {
+            "if_block" | "elseif_block" | "elseiftype_block" => {
+                format_if_block(child, source, state, opts)
+            }
+            "iftype" => {
+                state.write_node(child, source);
+                state.write_text(" ");
+            }
+            "<:" => {
+                state.write_text(" ");
+                state.write_node(child, source);
+                state.write_text(" ");
+            }
+            "then_block" => format_then_block(child, source, state, opts),
+            "else_block" => format_else_block(child, source, state, opts),
+            "end" => {
+                if state.current_line_has_content {
+                    state.write_newline();
+                }
+                state.write_node(child, source);
+                state.write_newline();
+            }
+            _ if !is_comment(child) => {
+                // The types compared by `iftype`
+                format_expression(child, source, state, opts);
+            }
+            _ => {}
         }
+    }
+}
 
-        _ => {
-            // For any unhandled nodes, recursively format children
-            for child in node.children(&mut node.walk()) {
+/// Format the keyword and condition of a branch: `if cond`, `ifdef cond` or
+/// `elseif cond`, and the `then` part of an `elseif`
+fn format_if_block(node: Node, source: &[u8], state: &mut FormatterState, opts: &FormatOptions) {
+    for child in node.children(&mut node.walk()) {
+        match child.kind() {
+            "elseif" => {
+                if state.current_line_has_content {
+                    state.write_newline();
+                }
+                state.write_node(child, source);
+                state.write_text(" ");
+            }
+            "if" | "ifdef" => {
+                state.write_node(child, source);
+                state.write_text(" ");
+            }
+            "block" => {
+                // The condition
+                format_inline_block(child, source, state, opts);
+            }
+            "<:" => {
+                state.write_text(" ");
+                state.write_node(child, source);
+                state.write_text(" ");
+            }
+            "then_block" => format_then_block(child, source, state, opts),
+            _ if !is_comment(child) => {
+                // The types compared by an `elseif` of `iftype`
+                format_expression(child, source, state, opts);
+            }
+            _ => {}
+        }
+    }
+}
+
+/// Format `then` and the body of a branch, indented below it
+fn format_then_block(node: Node, source: &[u8], state: &mut FormatterState, opts: &FormatOptions) {
+    for child in node.children(&mut node.walk()) {
+        match child.kind() {
+            "then" => {
+                state.write_text(" ");
+                state.write_node(child, source);
+                state.write_newline();
+            }
+            "block" => {
+                state.increase_indent();
                 format_node(child, source, state, opts);
+                state.decrease_indent();
             }
+            _ => {}
         }
     }
 }
 
-fn format_if_block(node: Node, source: &[u8], state: &mut FormatterState, _opts: &FormatOptions) {
-    let mut cursor = node.walk();
-    if cursor.goto_first_child() {
-        loop {
-            let child = cursor.node();
-            match child.kind() {
-                "if" => {
-                    state.write_text("if ");
+/// Format `recover cap ... end`, on one line if the body is a single
+/// expression that fits, otherwise with the body indented below `recover`
+fn format_recover(node: Node, source: &[u8], state: &mut FormatterState, opts: &FormatOptions) {
+    state.begin_group();
+    for child in node.children(&mut node.walk()) {
+        match child.kind() {
+            "recover" | "capability" => {
+                if child.kind() == "capability" {
+                    state.write_text(" ");
+                }
+                state.write_node(child, source);
+            }
+            "block" => {
+                let statements: Vec<_> = child
+                    .children(&mut child.walk())
+                    .filter(|c| !is_comment(*c))
+                    .collect();
+                state.increase_indent();
+                match statements[..] {
+                    [expression]
+                        if !is_control_flow(expression)
+                            && expression.kind() != "assignment_expression" =>
+                    {
+                        state.write_line();
+                        format_expression(expression, source, state, opts);
+                    }
+                    _ => {
+                        state.write_newline();
+                        format_node(child, source, state, opts);
+                    }
                 }
-                "block" => {
-                    // The condition
-                    state.write_text(&node_text(child, source));
+                state.decrease_indent();
+            }
+            "end" => {
+                if state.current_line_has_content {
+                    state.write_line();
+                }
+                state.write_node(child, source);
+            }
+            _ => {}
+        }
+    }
+    state.end_group();
+}
+
+/// Format a lambda on one line if it fits, otherwise with its body indented
+/// below the header and the closing `}` on a line of its own
+fn format_lambda(node: Node, source: &[u8], state: &mut FormatterState, opts: &FormatOptions) {
+    let mut after_body = false;
+    state.begin_group();
+    for child in node.children(&mut node.walk()) {
+        match child.kind() {
+            "{" | "(" | ")" => state.write_node(child, source),
+            "," => state.write_text(", "),
+            ":" => state.write_text(": "),
+            "?" => state.write_text(" ?"),
+            "=>" => state.write_text(" =>"),
+            "capability" if after_body => {
+                // The capability of the lambda object itself, after the `}`
+                state.write_text(" ");
+                state.write_node(child, source);
+            }
+            "lambda_parameter" | "lambda_captures" => format_separated(child, source, state, opts),
+            "block" => {
+                state.increase_indent();
+                state.write_line();
+                format_lambda_body(child, source, state, opts);
+                state.decrease_indent();
+                state.write_softline();
+            }
+            "}" => {
+                state.write_node(child, source);
+                after_body = true;
+            }
+            _ if !is_comment(child) => {
+                // The receiver capability and the return type
+                format_expression(child, source, state, opts);
+            }
+            _ => {}
+        }
+    }
+    state.end_group();
+}
+
+/// Format the expressions of a lambda body, one per line when the lambda
+/// does not fit on a single line
+///
+/// Expressions joined by `;` may share a line; expressions the source wrote
+/// on separate lines stay on separate lines, which breaks the lambda.
+fn format_lambda_body(node: Node, source: &[u8], state: &mut FormatterState, opts: &FormatOptions) {
+    // The expression written last, unless a `;` followed it
+    let mut prev: Option<Node> = None;
+    for child in node.children(&mut node.walk()) {
+        if is_comment(child) {
+            continue;
+        }
+        if child.kind() == ";" {
+            state.write_text(";");
+            state.write_line();
+            prev = None;
+            continue;
+        }
+        if let Some(prev) = prev {
+            if prev.end_position().row == child.start_position().row {
+                state.write_text(" ");
+            } else {
+                state.write_newline();
+            }
+        }
+        if child.kind() == "assignment_expression" || is_control_flow(child) {
+            format_node(child, source, state, opts);
+            state.reopen_line();
+        } else {
+            format_expression(child, source, state, opts);
+        }
+        prev = Some(child);
+    }
+}
+
+/// Format an object literal on one line if it fits, otherwise with its
+/// members indented like those of a class and `end` on a line of its own
+fn format_object_literal(
+    node: Node,
+    source: &[u8],
+    state: &mut FormatterState,
+    opts: &FormatOptions,
+) {
+    state.begin_group();
+    for child in node.children(&mut node.walk()) {
+        match child.kind() {
+            "object" | "capability" => {
+                if state.current_line_has_content && child.kind() != "object" {
                     state.write_text(" ");
                 }
-                _ => {}
+                state.write_node(child, source);
+            }
+            "is" => state.write_text(" is "),
+            "members" => {
+                state.increase_indent();
+                state.write_line();
+                format_node(child, source, state, opts);
+                state.reopen_line();
+                state.decrease_indent();
             }
-            if !cursor.goto_next_sibling() {
-                break;
+            "end" => {
+                state.write_line();
+                state.write_node(child, source);
             }
+            _ if !is_comment(child) => {
+                // The provided types after `is`
+                format_expression(child, source, state, opts);
+            }
+            _ => {}
         }
     }
+    state.end_group();
 }
 
-fn format_then_block(node: Node, source: &[u8], state: &mut FormatterState, opts: &FormatOptions) {
-    let mut cursor = node.walk();
-    if cursor.goto_first_child() {
-        loop {
-            let child = cursor.node();
-            match child.kind() {
-                "then" => {
-                    state.write_text("then");
-                    state.write_newline();
+/// Format a parameter or capture list given as a flat run of tokens, with
+/// a space after each `,` and `:` and around each `=`
+fn format_separated(node: Node, source: &[u8], state: &mut FormatterState, opts: &FormatOptions) {
+    for child in node.children(&mut node.walk()) {
+        match child.kind() {
+            "," => state.write_text(", "),
+            ":" => state.write_text(": "),
+            "=" => state.write_text(" = "),
+            _ => format_expression(child, source, state, opts),
+        }
+    }
+}
+
+/// Format a type, with its type arguments on one line if they fit and one
+/// per line otherwise
+fn format_type(node: Node, source: &[u8], state: &mut FormatterState) {
+    match node.kind() {
+        "type_args" | "generic_parameters" | "type_parameters" => {
+            let items: Vec<_> = node
+                .named_children(&mut node.walk())
+                .filter(|child| !is_comment(*child))
+                .collect();
+            state.begin_group();
+            state.write_text("[");
+            state.increase_indent();
+            state.write_softline();
+            for (i, item) in items.iter().enumerate() {
+                if i > 0 {
+                    state.write_text(",");
+                    state.write_line();
                 }
-                "block" => {
-                    // The then body
-                    state.increase_indent();
-                    format_node(child, source, state, opts);
-                    state.decrease_indent();
+                format_type(*item, source, state);
+            }
+            state.decrease_indent();
+            state.write_softline();
+            state.write_text("]");
+            state.mark_end(node.end_byte());
+            state.end_group();
+        }
+        "generic_parameter" => {
+            // `A`, `A: Constraint` or `A: Constraint = Default`
+            for child in node.children(&mut node.walk()) {
+                match child.kind() {
+                    ":" => state.write_text(": "),
+                    "=" => state.write_text(" = "),
+                    _ if is_type(child) => format_type(child, source, state),
+                    _ => state.write_node(child, source),
+                }
+            }
+        }
+        "base_type" => {
+            for child in node.children(&mut node.walk()) {
+                match child.kind() {
+                    "type_args" => format_type(child, source, state),
+                    _ => state.write_node(child, source),
+                }
+            }
+        }
+        "iso_type" | "trn_type" | "ref_type" | "val_type" | "box_type" | "tag_type"
+        | "read_type" | "send_type" | "share_type" | "alias_type" | "any_type" => {
+            // A type followed by a capability, e.g. `String ref`
+            for child in node.children(&mut node.walk()) {
+                if is_type(child) {
+                    format_type(child, source, state);
+                } else {
+                    state.write_text(" ");
+                    state.write_node(child, source);
+                }
+            }
+        }
+        "union_type" | "intersection_type" => {
+            let operator = if node.kind() == "union_type" {
+                " | "
+            } else {
+                " & "
+            };
+            // The grammar also nests unparenthesized unions, e.g. after `this->`
+            let parenthesized = node.child(0).is_some_and(|first| first.kind() == "(");
+            if parenthesized {
+                state.write_text("(");
+            }
+            for (i, member) in type_members(node).into_iter().enumerate() {
+                if i > 0 {
+                    state.write_text(operator);
                 }
-                _ => {}
+                format_type(member, source, state);
+            }
+            if parenthesized {
+                state.write_text(")");
+            }
+            state.mark_end(node.end_byte());
+        }
+        "tuple_type" => {
+            for child in node.children(&mut node.walk()) {
+                match child.kind() {
+                    "," => state.write_text(", "),
+                    _ if is_comment(child) => {}
+                    _ => format_type(child, source, state),
+                }
+            }
+        }
+        "viewpoint_type" => {
+            // `this->A`
+            for child in node.children(&mut node.walk()) {
+                match child.kind() {
+                    "->" | "this" => state.write_node(child, source),
+                    _ => format_type(child, source, state),
+                }
+            }
+        }
+        "ephemeral_type" | "aliased_type" => {
+            // A type followed by `^` or `!`
+            for child in node.children(&mut node.walk()) {
+                format_type(child, source, state);
             }
-            if !cursor.goto_next_sibling() {
-                break;
+        }
+        _ => state.write_node(node, source),
+    }
+}
+
+/// Format the union type of a type alias on the line of the alias if it
+/// fits, otherwise indented below it with one member per line and a leading
+/// `|`:
+///
+/// ```text
+/// type Message is
+///   ( Connect
+///   | Disconnect
+///   )
+/// ```
+fn format_union_alias(node: Node, source: &[u8], state: &mut FormatterState) {
+    state.begin_group();
+    state.increase_indent();
+    state.write_line();
+    state.write_text("(");
+    state.write_if_break(" ", "");
+    for (i, member) in type_members(node).into_iter().enumerate() {
+        if i > 0 {
+            state.write_line();
+            state.write_text("| ");
+        }
+        format_type(member, source, state);
+    }
+    state.write_softline();
+    state.write_text(")");
+    state.mark_end(node.end_byte());
+    state.decrease_indent();
+    state.end_group();
+}
+
+/// The members of a union or intersection type
+///
+/// The grammar nests `(A | B | C)` as `A | (B | C)` without parentheses
+/// around the inner union; those are flattened into one list.
+fn type_members(node: Node) -> Vec<Node> {
+    let mut members = Vec::new();
+    for child in node.children(&mut node.walk()) {
+        if is_comment(child) || matches!(child.kind(), "(" | ")" | "|" | "&") {
+            continue;
+        }
+        let nested =
+            child.kind() == node.kind() && child.child(0).is_some_and(|first| first.kind() != "(");
+        if nested {
+            members.extend(type_members(child));
+        } else {
+            members.push(child);
+        }
+    }
+    members
+}
+
+/// Whether `node` is a type
+fn is_type(node: Node) -> bool {
+    node.kind().ends_with("_type")
+}
+
+/// Format a docstring, re-indented to the level of the code around it
+///
+/// A docstring that fits on one line stays `"""like this"""`; any other is
+/// laid out with the opening and closing `"""` on lines of their own. With
+/// [`FormatOptions::wrap_docstrings`] prose paragraphs are also re-wrapped to
+/// the maximum line width.
+fn format_docstring(node: Node, source: &[u8], state: &mut FormatterState, opts: &FormatOptions) {
+    let text = node_text(node, source);
+    let Some(content) = text
+        .strip_prefix("\"\"\"")
+        .and_then(|text| text.strip_suffix("\"\"\""))
+    else {
+        // A plain string literal
+        state.write_node(node, source);
+        return;
+    };
+    let width = opts
+        .max_width
+        .saturating_sub(state.indent_level() * opts.indent_width);
+
+    let mut lines = docstring_lines(content);
+    if opts.wrap_docstrings {
+        lines = wrap_docstring(&lines, width);
+    }
+
+    state.write_comments_before(node.start_byte());
+    let one_line = match lines.as_slice() {
+        [line] => !content.contains('\n') && line.chars().count() + 6 <= width,
+        [] => true,
+        _ => false,
+    };
+    if one_line {
+        state.write_text(&format!("\"\"\"{}\"\"\"", lines.concat()));
+    } else {
+        state.write_text("\"\"\"");
+        state.write_newline();
+        for line in &lines {
+            if !line.is_empty() {
+                state.write_text(line);
             }
+            state.write_newline();
+        }
+        state.write_text("\"\"\"");
+    }
+    state.mark_end(node.end_byte());
+}
+
+/// The lines of a docstring with their common indentation and the blank
+/// lines around them removed
+fn docstring_lines(content: &str) -> Vec<String> {
+    let mut raw = content.lines();
+    // Text directly after the opening quotes has no indentation of its own
+    let first = raw.next().unwrap_or_default().trim();
+    let rest: Vec<_> = raw.map(str::trim_end).collect();
+    let indent = rest
+        .iter()
+        .filter(|line| !line.is_empty())
+        .map(|line| line.len() - line.trim_start().len())
+        .min()
+        .unwrap_or(0);
+
+    let mut lines = Vec::new();
+    if !first.is_empty() {
+        lines.push(first.to_string());
+    }
+    for line in rest {
+        lines.push(line.get(indent..).unwrap_or_default().to_string());
+    }
+    while lines.first().is_some_and(|line| line.is_empty()) {
+        lines.remove(0);
+    }
+    while lines.last().is_some_and(|line| line.is_empty()) {
+        lines.pop();
+    }
+    lines
+}
+
+/// Re-wrap the prose paragraphs of a docstring to `width` columns
+///
+/// Code blocks (fenced with ```` ``` ```` or indented), headings and blank
+/// lines are kept as they are; a list item is wrapped with its continuation
+/// lines indented below its text.
+fn wrap_docstring(lines: &[String], width: usize) -> Vec<String> {
+    let mut out = Vec::new();
+    let mut paragraph = Vec::new();
+    let mut hanging = 0;
+    let mut in_code = false;
+
+    for line in lines {
+        let trimmed = line.trim_start();
+        let indented = line.len() != trimmed.len();
+        if trimmed.starts_with("```") {
+            in_code = !in_code;
+        }
+        let verbatim = in_code
+            || trimmed.starts_with("```")
+            || trimmed.is_empty()
+            || trimmed.starts_with('#')
+            || (indented && paragraph.is_empty());
+        if verbatim {
+            fill_paragraph(&mut paragraph, hanging, width, &mut out);
+            out.push(line.clone());
+            continue;
+        }
+        if let Some(marker) = list_marker(trimmed) {
+            fill_paragraph(&mut paragraph, hanging, width, &mut out);
+            hanging = marker;
+        } else if paragraph.is_empty() {
+            hanging = 0;
+        }
+        paragraph.push(line.as_str());
+    }
+    fill_paragraph(&mut paragraph, hanging, width, &mut out);
+    out
+}
+
+/// Fill the words of `paragraph` into lines of at most `width` columns,
+/// indenting all but the first line by `hanging` columns
+fn fill_paragraph(paragraph: &mut Vec<&str>, hanging: usize, width: usize, out: &mut Vec<String>) {
+    let mut line = String::new();
+    let mut words_on_line = 0;
+    for word in paragraph.drain(..).flat_map(str::split_whitespace) {
+        if words_on_line > 0 && line.chars().count() + 1 + word.chars().count() > width {
+            out.push(std::mem::replace(&mut line, " ".repeat(hanging)));
+            words_on_line = 0;
+        }
+        if words_on_line > 0 {
+            line.push(' ');
         }
+        line.push_str(word);
+        words_on_line += 1;
     }
+    if words_on_line > 0 {
+        out.push(line);
+    }
+}
+
+/// Width of the marker starting a list item (`- `, `* ` or `1. `), if any
+fn list_marker(line: &str) -> Option<usize> {
+    if line.starts_with("- ") || line.starts_with("* ") || line.starts_with("+ ") {
+        return Some(2);
+    }
+    let digits = line.chars().take_while(char::is_ascii_digit).count();
+    (digits > 0 && line[digits..].starts_with(". ")).then_some(digits + 2)
+}
+
+/// Whether `node` is a docstring: a triple-quoted string directly inside a
+/// type or method declaration, or the first expression of a method body
+/// that has more expressions after it (as in ponyc, a string that is the
+/// whole body is the method's result)
+pub(crate) fn is_docstring(node: Node, source: &[u8]) -> bool {
+    let is_declaration = |node: Node| {
+        matches!(
+            node.kind(),
+            "actor_definition"
+                | "class_definition"
+                | "primitive_definition"
+                | "trait_definition"
+                | "interface_definition"
+                | "struct_definition"
+                | "method"
+                | "constructor"
+                | "behavior"
+                | "function_definition"
+        )
+    };
+    if node.kind() != "string" || !source[node.start_byte()..].starts_with(b"\"\"\"") {
+        return false;
+    }
+    let Some(parent) = node.parent() else {
+        return false;
+    };
+    if is_declaration(parent) {
+        return true;
+    }
+    let expressions: Vec<_> = parent
+        .named_children(&mut parent.walk())
+        .filter(|child| !is_comment(*child))
+        .collect();
+    parent.kind() == "block"
+        && expressions.len() > 1
+        && expressions[0] == node
+        && parent.parent().is_some_and(is_declaration)
+}
+
+/// Whether `node` is a control flow expression laid out over several lines
+fn is_control_flow(node: Node) -> bool {
+    matches!(
+        node.kind(),
+        "if_statement"
+            | "iftype_statement"
+            | "match_statement"
+            | "while_statement"
+            | "for_statement"
+            | "repeat_statement"
+            | "try_statement"
+            | "with_statement"
+    )
+}
+
+/// Whether `node` is a comment
+///
+/// tree-sitter also marks some ERROR nodes as extras, so [`Node::is_extra`]
+/// cannot tell comments from source that must be kept.
+fn is_comment(node: Node) -> bool {
+    matches!(node.kind(), "line_comment" | "block_comment")
 }
 
 fn node_text(node: Node, source: &[u8]) -> String {
@@ -0,0 +1,143 @@
+//! Changed lines from the local git repository
+//!
+//! `ponyfmt fmt --changed-since <rev>` only formats the lines touched since a
+//! revision. The hunks come from `git diff --unified=0` run on the machine,
+//! which compares the working tree (including uncommitted changes) with the
+//! revision. Untracked files are new since any revision, so they are
+//! formatted as a whole.
+
+use anyhow::{Context, Result, bail};
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+/// Added or modified line ranges (1-based, inclusive) of each changed file,
+/// keyed by canonical path; untracked files have no ranges, meaning the
+/// whole file
+pub fn changed_lines(rev: &str) -> Result<HashMap<PathBuf, Vec<(usize, usize)>>> {
+    if rev.starts_with('-') {
+        bail!("invalid revision `{}`", rev);
+    }
+    let toplevel = git(&["rev-parse", "--show-toplevel"])?;
+    let toplevel = PathBuf::from(toplevel.trim());
+    let diff = git(&[
+        "diff",
+        "--unified=0",
+        "--no-color",
+        "--no-ext-diff",
+        // `diff.noprefix` and `diff.mnemonicPrefix` would change the paths
+        "--src-prefix=a/",
+        "--dst-prefix=b/",
+        rev,
+        "--",
+    ])?;
+    let untracked = git(&[
+        "-C",
+        &toplevel.to_string_lossy(),
+        "ls-files",
+        "--others",
+        "--exclude-standard",
+        "-z",
+    ])?;
+
+    let mut changed = HashMap::new();
+    for (path, lines) in parse_diff(&diff) {
+        // Files deleted in the working tree have nothing left to format
+        if let Ok(path) = toplevel.join(path).canonicalize() {
+            changed.insert(path, lines);
+        }
+    }
+    for path in untracked.split('\0').filter(|path| !path.is_empty()) {
+        if let Ok(path) = toplevel.join(path).canonicalize() {
+            changed.insert(path, Vec::new());
+        }
+    }
+    Ok(changed)
+}
+
+fn git(args: &[&str]) -> Result<String> {
+    let output = Command::new("git")
+        .args(args)
+        .output()
+        .context("failed to run git")?;
+    if !output.status.success() {
+        bail!(
+            "git {} failed: {}",
+            args.join(" "),
+            String::from_utf8_lossy(&output.stderr).trim()
+        );
+    }
+    Ok(String::from_utf8_lossy(&output.stdout).into_owned())
+}
+
+/// Parse a zero-context unified diff into the new-side line ranges of each
+/// file, with paths relative to the repository root
+fn parse_diff(diff: &str) -> Vec<(PathBuf, Vec<(usize, usize)>)> {
+    let mut files: Vec<(PathBuf, Vec<(usize, usize)>)> = Vec::new();
+    let mut in_file = false;
+    for line in diff.lines() {
+        if let Some(path) = line.strip_prefix("+++ ") {
+            in_file = path != "/dev/null";
+            if in_file {
+                let path = path.strip_prefix("b/").unwrap_or(path);
+                files.push((Path::new(path).to_path_buf(), Vec::new()));
+            }
+        } else if let Some(hunk) = line.strip_prefix("@@ ")
+            && in_file
+            && let Some(range) = parse_hunk(hunk)
+            && let Some((_, lines)) = files.last_mut()
+        {
+            lines.push(range);
+        }
+    }
+    files.retain(|(_, lines)| !lines.is_empty());
+    files
+}
+
+/// New-side line range of a hunk header (`-a,b +c,d @@`); `None` for pure deletions
+fn parse_hunk(hunk: &str) -> Option<(usize, usize)> {
+    let new_side = hunk.split(' ').find_map(|part| part.strip_prefix('+'))?;
+    let (start, count) = match new_side.split_once(',') {
+        Some((start, count)) => (start.parse().ok()?, count.parse().ok()?),
+        None => (new_side.parse().ok()?, 1),
+    };
+    if count == 0 {
+        return None;
+    }
+    Some((start, start + count - 1))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn hunks_are_collected_per_file() {
+        let diff = "\
+diff --git a/src/main.pony b/src/main.pony
+index 1111111..2222222 100644
+--- a/src/main.pony
++++ b/src/main.pony
+@@ -3 +3 @@ actor Main
+-    foo()
++    foo( )
+@@ -10,0 +11,3 @@ actor Main
++    a()
++    b()
++    c()
+@@ -20,2 +23,0 @@ actor Main
+-    d()
+-    e()
+diff --git a/old.pony b/old.pony
+deleted file mode 100644
+--- a/old.pony
++++ /dev/null
+@@ -1 +0,0 @@
+-actor Old
+";
+        assert_eq!(
+            parse_diff(diff),
+            vec![(PathBuf::from("src/main.pony"), vec![(3, 3), (11, 13)])]
+        );
+    }
+}
@@ -8,8 +8,12 @@
 //! ## Status
 //!
 //! This is an early prototype. The formatter implements basic indentation and whitespace
-//! normalization, but is not yet idempotent or specification-complete. It should be
-//! considered experimental and used with caution on production code.
+//! normalization, but is not yet specification-complete. It should be considered
+//! experimental and used with caution on production code.
+//!
+//! Formatting is meant to be idempotent. Setting `verify` in
+//! [`formatter::FormatOptions`] formats the output a second time and returns a
+//! [`error::FormatError::Unstable`] error, carrying a diff, if the two passes differ.
 //!
 //! ## Usage
 //!
@@ -26,7 +30,10 @@
 //!
 //! let opts = FormatOptions {
 //!     indent_width: 2,
+//!     max_width: 100,
 //!     mode: Mode::Stdout,
+//!     verify: true,
+//!     ..FormatOptions::default()
 //! };
 //!
 //! let formatted = format_source(pony_source, &opts).unwrap();
@@ -42,12 +49,15 @@
 //!
 //! - [`parser`] - Tree-sitter integration and Pony source parsing
 //! - [`formatter`] - Core formatting logic and public API
+//! - [`equivalence`] - Token-level check that formatting preserved the program
+//! - [`config`] - `ponyfmt.toml` discovery and per-directory overrides
+//! - [`error`] - The [`error::FormatError`] type returned by the library
+//! - [`diagnostics`] - Syntax error reports with source snippets
 //!
 //! ## Limitations
 //!
-//! - Formatting is not idempotent (running twice may produce different results)
-//! - Limited comment preservation
-//! - Basic error recovery for malformed source
+//! - Source with syntax errors is only formatted on a best-effort basis, when
+//!   [`formatter::FormatOptions::allow_errors`] is set
 //! - Performance not optimized for very large files
 
 /// Tree-sitter based Pony language parser
@@ -56,5 +66,23 @@ pub mod parser;
 /// Core formatting engine and public API
 pub mod formatter;
 
+/// Safety check that formatted output is the same program as its input
+pub mod equivalence;
+
+/// Error type of the parser and formatter
+pub mod error;
+
+/// Rustc-style reports of syntax errors in the parsed source
+pub mod diagnostics;
+
+/// Discovery and merging of `ponyfmt.toml` configuration files
+pub mod config;
+
+/// Document IR and width-aware layout engine used by the formatter
+mod doc;
+
+/// Comment collection and attachment used by the formatter
+mod trivia;
+
 #[cfg(test)]
 mod debug;
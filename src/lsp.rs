@@ -0,0 +1,390 @@
+//! Language Server Protocol formatting server
+//!
+//! `ponyfmt lsp` speaks LSP (JSON-RPC with `Content-Length` framing) over
+//! stdio, so every editor with an LSP client can use the same integration.
+//! Open documents are kept in memory (full text sync) and the server answers
+//! `textDocument/formatting`, `textDocument/rangeFormatting` and
+//! `textDocument/onTypeFormatting` with a single edit replacing the part of
+//! the document that changed. Range and on-type formatting return no edits
+//! while the document has syntax errors.
+//!
+//! Style options come from the `ponyfmt.toml` files that apply to the
+//! document, like on the command line; the editor's `tabSize` is ignored so
+//! that every editor formats a project the same way.
+
+use crate::config;
+use crate::equivalence::check_equivalence;
+use crate::error::FormatError;
+use crate::formatter::{FormatOptions, format_range, format_source};
+use anyhow::{Context, Result, bail};
+use serde_json::{Value, json};
+use std::collections::HashMap;
+use std::fmt;
+use std::io::{self, BufRead, Write};
+use std::ops::Range;
+use std::path::PathBuf;
+
+const METHOD_NOT_FOUND: i64 = -32601;
+const INVALID_PARAMS: i64 = -32602;
+const REQUEST_FAILED: i64 = -32803;
+
+/// Error returned to the client in a response
+#[derive(Debug)]
+struct ResponseError {
+    code: i64,
+    message: String,
+}
+
+impl ResponseError {
+    fn new(code: i64, message: impl Into<String>) -> Self {
+        Self {
+            code,
+            message: message.into(),
+        }
+    }
+
+    fn request_failed(error: impl fmt::Display) -> Self {
+        Self::new(REQUEST_FAILED, error.to_string())
+    }
+}
+
+#[derive(Debug, Default)]
+struct Server {
+    /// Text of the open documents by URI
+    documents: HashMap<String, String>,
+    shutdown: bool,
+}
+
+/// Serve LSP requests on stdin/stdout until the client sends `exit`
+pub fn run() -> Result<()> {
+    let mut input = io::stdin().lock();
+    let mut output = io::stdout().lock();
+    let mut server = Server::default();
+
+    while let Some(message) = read_message(&mut input)? {
+        // Responses to requests we never send are ignored
+        let Some(method) = message.get("method").and_then(Value::as_str) else {
+            continue;
+        };
+        if method == "exit" {
+            if !server.shutdown {
+                bail!("the client exited without a shutdown request");
+            }
+            return Ok(());
+        }
+
+        let result = server.handle(method, &message["params"]);
+        // Only requests carry an id and get a response
+        if let Some(id) = message.get("id") {
+            let response = match result {
+                Ok(result) => json!({ "jsonrpc": "2.0", "id": id, "result": result }),
+                Err(error) => json!({
+                    "jsonrpc": "2.0",
+                    "id": id,
+                    "error": { "code": error.code, "message": error.message },
+                }),
+            };
+            write_message(&mut output, &response)?;
+        }
+    }
+    Ok(())
+}
+
+impl Server {
+    fn handle(&mut self, method: &str, params: &Value) -> Result<Value, ResponseError> {
+        match method {
+            "initialize" => Ok(json!({
+                "capabilities": {
+                    "textDocumentSync": 1,
+                    "documentFormattingProvider": true,
+                    "documentRangeFormattingProvider": true,
+                    "documentOnTypeFormattingProvider": { "firstTriggerCharacter": "\n" },
+                },
+                "serverInfo": { "name": "ponyfmt", "version": env!("CARGO_PKG_VERSION") },
+            })),
+            "shutdown" => {
+                self.shutdown = true;
+                Ok(Value::Null)
+            }
+            "textDocument/didOpen" => {
+                let document = &params["textDocument"];
+                if let (Some(uri), Some(text)) =
+                    (document["uri"].as_str(), document["text"].as_str())
+                {
+                    self.documents.insert(uri.to_string(), text.to_string());
+                }
+                Ok(Value::Null)
+            }
+            "textDocument/didChange" => {
+                // Full sync: the last change holds the whole document
+                let text = params["contentChanges"]
+                    .as_array()
+                    .and_then(|changes| changes.last())
+                    .and_then(|change| change["text"].as_str());
+                if let (Some(uri), Some(text)) = (params["textDocument"]["uri"].as_str(), text) {
+                    self.documents.insert(uri.to_string(), text.to_string());
+                }
+                Ok(Value::Null)
+            }
+            "textDocument/didClose" => {
+                if let Some(uri) = params["textDocument"]["uri"].as_str() {
+                    self.documents.remove(uri);
+                }
+                Ok(Value::Null)
+            }
+            "textDocument/formatting" => {
+                self.format(params, |text, opts| Ok(format_source(text, opts)?))
+            }
+            "textDocument/rangeFormatting" => {
+                let range = &params["range"];
+                self.format(params, |text, opts| {
+                    let start = offset(text, &range["start"])?;
+                    let end = offset(text, &range["end"])?;
+                    // A range past the end of the document has nothing to format
+                    if start == text.len() {
+                        return Ok(text.to_string());
+                    }
+                    format_part(text, start..end.max(start), opts)
+                })
+            }
+            "textDocument/onTypeFormatting" => {
+                // Format the line a newline just completed, or the line being typed
+                let position = &params["position"];
+                let newline = params["ch"].as_str() == Some("\n");
+                self.format(params, |text, opts| {
+                    let line = position["line"].as_u64().context("missing position")? as usize;
+                    let line = if newline {
+                        line.saturating_sub(1)
+                    } else {
+                        line
+                    };
+                    match line_range(text, line) {
+                        Some(range) => format_part(text, range, opts),
+                        None => Ok(text.to_string()),
+                    }
+                })
+            }
+            _ if method.starts_with("$/") => Ok(Value::Null),
+            _ => Err(ResponseError::new(
+                METHOD_NOT_FOUND,
+                format!("unsupported method {}", method),
+            )),
+        }
+    }
+
+    /// Format the document named in `params` and return the edits to apply
+    fn format(
+        &self,
+        params: &Value,
+        format: impl FnOnce(&str, &FormatOptions) -> Result<String>,
+    ) -> Result<Value, ResponseError> {
+        let uri = params["textDocument"]["uri"]
+            .as_str()
+            .ok_or_else(|| ResponseError::new(INVALID_PARAMS, "missing textDocument.uri"))?;
+        let text = self
+            .documents
+            .get(uri)
+            .ok_or_else(|| ResponseError::new(INVALID_PARAMS, format!("{} is not open", uri)))?;
+
+        let mut opts = FormatOptions::default();
+        if let Some(path) = uri_to_path(uri) {
+            config::resolve(&path)
+                .map_err(ResponseError::request_failed)?
+                .config
+                .apply(&mut opts);
+        }
+
+        let formatted = format(text, &opts).map_err(ResponseError::request_failed)?;
+        if formatted == *text {
+            return Ok(json!([]));
+        }
+        // The editor replaces its buffer with the result, so it must be the same program
+        check_equivalence(text, &formatted).map_err(ResponseError::request_failed)?;
+        Ok(json!([minimal_edit(text, &formatted)]))
+    }
+}
+
+/// Format the part of `text` covering `range`
+///
+/// A buffer being edited often has syntax errors; range and on-type
+/// formatting then leave it as it is instead of failing the request.
+fn format_part(text: &str, range: Range<usize>, opts: &FormatOptions) -> Result<String> {
+    match format_range(text, range, opts) {
+        Err(FormatError::Syntax { .. }) => Ok(text.to_string()),
+        result => Ok(result?),
+    }
+}
+
+/// Read one `Content-Length` framed message; `None` at end of input
+fn read_message(input: &mut impl BufRead) -> Result<Option<Value>> {
+    let mut content_length = None;
+    loop {
+        let mut header = String::new();
+        if input.read_line(&mut header)? == 0 {
+            return Ok(None);
+        }
+        let header = header.trim_end();
+        if header.is_empty() {
+            break;
+        }
+        if let Some((name, value)) = header.split_once(':')
+            && name.eq_ignore_ascii_case("Content-Length")
+        {
+            content_length = Some(
+                value
+                    .trim()
+                    .parse::<usize>()
+                    .context("invalid Content-Length")?,
+            );
+        }
+    }
+    let content_length = content_length.context("message without Content-Length header")?;
+    let mut body = vec![0; content_length];
+    input.read_exact(&mut body)?;
+    Ok(Some(
+        serde_json::from_slice(&body).context("invalid JSON-RPC message")?,
+    ))
+}
+
+fn write_message(output: &mut impl Write, message: &Value) -> Result<()> {
+    let body = message.to_string();
+    write!(output, "Content-Length: {}\r\n\r\n{}", body.len(), body)?;
+    output.flush()?;
+    Ok(())
+}
+
+/// A single edit turning `old` into `new`, covering only the part that differs
+fn minimal_edit(old: &str, new: &str) -> Value {
+    let mut prefix = old
+        .bytes()
+        .zip(new.bytes())
+        .take_while(|(a, b)| a == b)
+        .count();
+    while !old.is_char_boundary(prefix) || !new.is_char_boundary(prefix) {
+        prefix -= 1;
+    }
+    let max_suffix = (old.len() - prefix).min(new.len() - prefix);
+    let mut suffix = old
+        .bytes()
+        .rev()
+        .zip(new.bytes().rev())
+        .take(max_suffix)
+        .take_while(|(a, b)| a == b)
+        .count();
+    while !old.is_char_boundary(old.len() - suffix) || !new.is_char_boundary(new.len() - suffix) {
+        suffix -= 1;
+    }
+
+    json!({
+        "range": {
+            "start": position(old, prefix),
+            "end": position(old, old.len() - suffix),
+        },
+        "newText": &new[prefix..new.len() - suffix],
+    })
+}
+
+/// LSP position (line and UTF-16 column) of byte `offset` of `text`
+fn position(text: &str, offset: usize) -> Value {
+    let before = &text[..offset];
+    let line = before.matches('\n').count();
+    let line_start = before.rfind('\n').map_or(0, |i| i + 1);
+    let character = before[line_start..].encode_utf16().count();
+    json!({ "line": line, "character": character })
+}
+
+/// Byte offset of an LSP position in `text`, clamped to the end of its line
+fn offset(text: &str, position: &Value) -> Result<usize> {
+    let line = position["line"].as_u64().context("missing position line")? as usize;
+    let character = position["character"]
+        .as_u64()
+        .context("missing position character")? as usize;
+    let Some(Range { start, end }) = line_range(text, line) else {
+        return Ok(text.len());
+    };
+    let mut units = 0;
+    for (i, c) in text[start..end].char_indices() {
+        if units >= character {
+            return Ok(start + i);
+        }
+        units += c.len_utf16();
+    }
+    Ok(end)
+}
+
+/// Byte range of line `line` of `text`, without its line break; `None` if
+/// the document has no such line
+fn line_range(text: &str, line: usize) -> Option<Range<usize>> {
+    let start = if line == 0 {
+        0
+    } else {
+        text.match_indices('\n').nth(line - 1).map(|(i, _)| i + 1)?
+    };
+    let end = text[start..].find('\n').map_or(text.len(), |i| start + i);
+    Some(start..end)
+}
+
+/// Local path of a `file://` URI
+fn uri_to_path(uri: &str) -> Option<PathBuf> {
+    let path = uri.strip_prefix("file://")?;
+    let mut bytes = Vec::with_capacity(path.len());
+    let mut rest = path.as_bytes();
+    while let Some((&b, tail)) = rest.split_first() {
+        if b == b'%'
+            && let Some(hex) = tail.get(..2)
+            && let Ok(byte) = u8::from_str_radix(std::str::from_utf8(hex).ok()?, 16)
+        {
+            bytes.push(byte);
+            rest = &tail[2..];
+            continue;
+        }
+        bytes.push(b);
+        rest = tail;
+    }
+    Some(PathBuf::from(String::from_utf8(bytes).ok()?))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn minimal_edit_covers_only_the_change() {
+        let edit = minimal_edit(
+            "actor Main\n  fun  f() => é\n",
+            "actor Main\n  fun f() => é\n",
+        );
+        assert_eq!(
+            edit,
+            json!({
+                "range": {
+                    "start": { "line": 1, "character": 6 },
+                    "end": { "line": 1, "character": 7 },
+                },
+                "newText": "",
+            })
+        );
+    }
+
+    #[test]
+    fn positions_count_utf16_units() {
+        let text = "a\n\u{1F600}b\nc";
+        let position = json!({ "line": 1, "character": 2 });
+        assert_eq!(offset(text, &position).unwrap(), text.find('b').unwrap());
+        assert_eq!(super::position(text, text.find('b').unwrap()), position);
+    }
+
+    #[test]
+    fn lines_past_the_end_have_no_range() {
+        assert_eq!(line_range("a\nbc\n", 1), Some(2..4));
+        assert_eq!(line_range("a\nbc", 5), None);
+    }
+
+    #[test]
+    fn file_uris_are_percent_decoded() {
+        assert_eq!(
+            uri_to_path("file:///home/me/my%20project/main.pony"),
+            Some(PathBuf::from("/home/me/my project/main.pony"))
+        );
+    }
+}
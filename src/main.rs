@@ -1,11 +1,27 @@
+mod config;
+mod diagnostics;
+mod doc;
+mod equivalence;
+mod error;
 mod formatter;
+mod git;
+mod lsp;
 mod parser;
+mod trivia;
 
 use anyhow::{Result, bail};
 use clap::{Parser, Subcommand};
-use formatter::{FormatOptions, Mode, format_source};
+use config::Config;
+use equivalence::check_equivalence;
+use error::FormatError;
+use formatter::{FormatOptions, Mode, format_range, format_source};
 use rayon::prelude::*;
+use similar::TextDiff;
+use std::collections::HashMap;
+use std::collections::hash_map::Entry;
 use std::fs;
+use std::io::{self, IsTerminal, Read};
+use std::ops::Range;
 use std::path::{Path, PathBuf};
 use walkdir::WalkDir;
 
@@ -19,22 +35,54 @@ struct Cli {
 #[derive(Subcommand, Debug)]
 enum Commands {
     Fmt {
-        /// Paths (files or directories) to format (defaults to current dir)
+        /// Paths (files or directories) to format (defaults to current dir);
+        /// `-` reads from stdin
         paths: Vec<PathBuf>,
+        /// Read source from stdin and write only the formatted code to stdout
+        #[arg(long)]
+        stdin: bool,
+        /// Path of the file read from stdin, used for config discovery and messages
+        #[arg(long, value_name = "PATH")]
+        stdin_filepath: Option<PathBuf>,
         /// Write the formatted content back to the files
         #[arg(long)]
         write: bool,
         /// Check if files are formatted; non-zero exit if changes needed
         #[arg(long)]
         check: bool,
-        /// Indent width
-        #[arg(long, default_value_t = 2)]
-        indent: usize,
+        /// Print a unified diff of the changes; non-zero exit if changes needed
+        #[arg(long)]
+        diff: bool,
+        /// Indent width (overrides config files; defaults to 2)
+        #[arg(long)]
+        indent: Option<usize>,
+        /// Maximum line width (overrides config files; defaults to 100)
+        #[arg(long)]
+        max_width: Option<usize>,
+        /// Format each file twice and fail if the second pass changes the output
+        #[arg(long)]
+        verify: bool,
+        /// Format files with syntax errors instead of reporting them
+        #[arg(long)]
+        allow_errors: bool,
+        /// Only format the statements and members covering these lines (1-based, inclusive)
+        #[arg(long, value_name = "START:END", value_parser = parse_line_range)]
+        lines: Option<(usize, usize)>,
+        /// Only format the lines changed since this git revision
+        #[arg(long, value_name = "REV")]
+        changed_since: Option<String>,
     },
     Debug {
         /// File to debug
         file: PathBuf,
     },
+    Config {
+        /// Print the effective configuration for this file or directory
+        #[arg(long, value_name = "FILE")]
+        show: PathBuf,
+    },
+    /// Run a Language Server Protocol formatting server over stdio
+    Lsp,
 }
 
 fn main() -> Result<()> {
@@ -42,24 +90,77 @@ fn main() -> Result<()> {
     match cli.command {
         Commands::Fmt {
             paths,
+            stdin,
+            stdin_filepath,
             write,
             check,
+            diff,
             indent,
+            max_width,
+            verify,
+            allow_errors,
+            lines,
+            changed_since,
         } => {
-            if write && check {
-                bail!("--write and --check are mutually exclusive");
+            if [write, check, diff].iter().filter(|&&flag| flag).count() > 1 {
+                bail!("--write, --check and --diff are mutually exclusive");
             }
             let mode = if write {
                 Mode::Write
             } else if check {
                 Mode::Check
+            } else if diff {
+                Mode::Diff
             } else {
                 Mode::Stdout
             };
             let opts = FormatOptions {
-                indent_width: indent,
                 mode,
+                verify,
+                allow_errors,
+                ..FormatOptions::default()
             };
+            // Command-line options take precedence over config files
+            let overrides = Config {
+                indent_width: indent,
+                max_width,
+                ..Config::default()
+            };
+            overrides.validate()?;
+
+            let stdin = stdin || paths.iter().any(|p| p.as_os_str() == "-");
+            if stdin_filepath.is_some() && !stdin {
+                bail!("--stdin-filepath requires formatting stdin (`-` or --stdin)");
+            }
+            if stdin {
+                if paths.iter().any(|p| p.as_os_str() != "-") {
+                    bail!("paths cannot be combined with formatting stdin");
+                }
+                if write {
+                    bail!("--write cannot be used when formatting stdin");
+                }
+                if changed_since.is_some() {
+                    bail!("--changed-since cannot be used when formatting stdin");
+                }
+                let changed = match format_stdin(
+                    stdin_filepath.as_deref(),
+                    &opts,
+                    &overrides,
+                    lines.as_slice(),
+                ) {
+                    Ok(changed) => changed,
+                    Err(e) => {
+                        let name = stdin_filepath.as_deref().unwrap_or(Path::new("<stdin>"));
+                        report_error(name, &e);
+                        std::process::exit(1);
+                    }
+                };
+                if matches!(mode, Mode::Check | Mode::Diff) && changed {
+                    std::process::exit(1);
+                }
+                return Ok(());
+            }
+
             let targets = if paths.is_empty() {
                 vec![PathBuf::from(".")]
             } else {
@@ -70,28 +171,132 @@ fn main() -> Result<()> {
                 collect_pony_files(&p, &mut pony_files);
             }
 
-            let results: Vec<_> = pony_files
+            // Line ranges to format in each file; empty means the whole file
+            let jobs: Vec<(PathBuf, Vec<(usize, usize)>)> = match &changed_since {
+                Some(rev) => {
+                    if lines.is_some() {
+                        bail!("--lines and --changed-since are mutually exclusive");
+                    }
+                    let mut changed = git::changed_lines(rev)?;
+                    pony_files
+                        .into_iter()
+                        .filter_map(|path| {
+                            let lines = changed.remove(&path.canonicalize().ok()?)?;
+                            Some((path, lines))
+                        })
+                        .collect()
+                }
+                None => pony_files
+                    .into_iter()
+                    .map(|path| (path, lines.into_iter().collect()))
+                    .collect(),
+            };
+
+            // Files in the same directory share their configuration
+            let mut dir_opts: HashMap<PathBuf, FormatOptions> = HashMap::new();
+            for (path, _) in &jobs {
+                let dir = path.parent().unwrap_or(Path::new(".")).to_path_buf();
+                if let Entry::Vacant(entry) = dir_opts.entry(dir) {
+                    entry.insert(options_for(path, &opts, &overrides)?);
+                }
+            }
+
+            let results: Vec<_> = jobs
                 .par_iter()
-                .map(|path| process_file(path, &opts))
+                .map(|(path, lines)| {
+                    let dir = path.parent().unwrap_or(Path::new("."));
+                    (path, process_file(path, &dir_opts[dir], lines))
+                })
                 .collect();
             let mut had_change = false;
-            for r in results {
+            let mut had_error = false;
+            for (path, r) in results {
                 match r {
                     Ok(changed) => had_change |= changed,
-                    Err(e) => eprintln!("{}", e),
+                    Err(e) => {
+                        report_error(path, &e);
+                        had_error = true;
+                    }
                 }
             }
-            if matches!(mode, Mode::Check) && had_change {
+            if had_error || (matches!(mode, Mode::Check | Mode::Diff) && had_change) {
                 std::process::exit(1);
             }
         }
         Commands::Debug { file } => {
             debug_file(&file)?;
         }
+        Commands::Config { show } => {
+            show_config(&show)?;
+        }
+        Commands::Lsp => {
+            lsp::run()?;
+        }
     }
     Ok(())
 }
 
+/// Resolve the formatting options for `path` from its config files, with
+/// `overrides` applied on top
+fn options_for(path: &Path, base: &FormatOptions, overrides: &Config) -> Result<FormatOptions> {
+    let mut config = config::resolve(path)?.config;
+    config.merge(overrides.clone());
+    let mut opts = base.clone();
+    config.apply(&mut opts);
+    Ok(opts)
+}
+
+/// Format source read from stdin, printing only the formatted code
+///
+/// `filepath` stands in for the path of the source when looking up config
+/// files. Output that is not the same program as the input is refused, since
+/// editors replace their buffer with it.
+fn format_stdin(
+    filepath: Option<&Path>,
+    base: &FormatOptions,
+    overrides: &Config,
+    lines: &[(usize, usize)],
+) -> Result<bool> {
+    let opts = options_for(filepath.unwrap_or(Path::new(".")), base, overrides)?;
+    let name = filepath.unwrap_or(Path::new("<stdin>"));
+    let mut content = String::new();
+    io::stdin().read_to_string(&mut content)?;
+    let formatted = format_content(&content, &opts, lines).map_err(|e| e.with_path(name))?;
+    let changed = formatted != content;
+    if changed {
+        check_equivalence(&content, &formatted).map_err(|e| e.with_path(name))?;
+    }
+    match opts.mode {
+        Mode::Stdout => print!("{}", formatted),
+        Mode::Diff if changed => {
+            print!(
+                "{}",
+                render_diff(&name.display().to_string(), &content, &formatted)
+            );
+        }
+        _ => {}
+    }
+    Ok(changed)
+}
+
+fn show_config(path: &Path) -> Result<()> {
+    let resolved = config::resolve(path)?;
+    if resolved.sources.is_empty() {
+        println!("# no config file found, using defaults");
+    }
+    for source in &resolved.sources {
+        println!("# {}", source.display());
+    }
+    let mut opts = FormatOptions::default();
+    resolved.config.apply(&mut opts);
+    println!("indent_width = {}", opts.indent_width);
+    println!("max_width = {}", opts.max_width);
+    println!("max_blank_lines = {}", opts.max_blank_lines);
+    println!("sort_uses = {}", opts.sort_uses);
+    println!("wrap_docstrings = {}", opts.wrap_docstrings);
+    Ok(())
+}
+
 fn debug_file(path: &Path) -> Result<()> {
     let content = fs::read_to_string(path)?;
     let tree = parser::parse(&content)?;
@@ -140,9 +345,9 @@ fn collect_pony_files(path: &Path, out: &mut Vec<PathBuf>) {
     }
 }
 
-fn process_file(path: &Path, opts: &FormatOptions) -> Result<bool> {
+fn process_file(path: &Path, opts: &FormatOptions, lines: &[(usize, usize)]) -> Result<bool> {
     let content = fs::read_to_string(path)?;
-    let formatted = format_source(&content, opts)?;
+    let formatted = format_content(&content, opts, lines).map_err(|e| e.with_path(path))?;
     let changed = formatted != content;
     match opts.mode {
         Mode::Stdout => {
@@ -151,10 +356,130 @@ fn process_file(path: &Path, opts: &FormatOptions) -> Result<bool> {
         }
         Mode::Write => {
             if changed {
+                // Refuse to write output that is not the same program
+                check_equivalence(&content, &formatted).map_err(|e| e.with_path(path))?;
                 fs::write(path, formatted)?;
             }
         }
+        Mode::Diff => {
+            if changed {
+                print!(
+                    "{}",
+                    render_diff(&path.display().to_string(), &content, &formatted)
+                );
+            }
+        }
         Mode::Check => {}
     }
     Ok(changed)
 }
+
+/// Print an error about the file at `path`
+///
+/// Formatter errors already carry the path, as `path:line:col: message`.
+fn report_error(path: &Path, error: &anyhow::Error) {
+    match error.downcast_ref::<FormatError>() {
+        Some(error @ FormatError::Syntax { .. }) => {
+            eprintln!("{}\n", error);
+            eprintln!(
+                "{}: not formatted because of syntax errors (use --allow-errors to format anyway)",
+                path.display()
+            );
+        }
+        Some(error) if error.path().is_some() => eprintln!("{}", error),
+        _ => eprintln!("{}: {}", path.display(), error),
+    }
+}
+
+/// Format `content`, or only the given line ranges of it if there are any
+fn format_content(
+    content: &str,
+    opts: &FormatOptions,
+    lines: &[(usize, usize)],
+) -> Result<String, FormatError> {
+    if lines.is_empty() {
+        return format_source(content, opts);
+    }
+    // Format the last range first so the line numbers of earlier ranges stay valid
+    let mut ranges = lines.to_vec();
+    ranges.sort_unstable();
+    let mut formatted = content.to_string();
+    for &(first, last) in ranges.iter().rev() {
+        // Lines past the end of the file have nothing to format
+        if let Some(range) = line_byte_range(&formatted, first, last) {
+            formatted = format_range(&formatted, range, opts)?;
+        }
+    }
+    Ok(formatted)
+}
+
+/// Byte range from the start of line `first` to the end of line `last`
+/// (1-based); `None` if the file has no line `first`
+fn line_byte_range(content: &str, first: usize, last: usize) -> Option<Range<usize>> {
+    let line_starts: Vec<usize> = std::iter::once(0)
+        .chain(content.match_indices('\n').map(|(i, _)| i + 1))
+        .collect();
+    let start = line_starts
+        .get(first - 1)
+        .copied()
+        .filter(|&start| start < content.len())?;
+    let end = line_starts
+        .get(last)
+        .map_or(content.len(), |&next| next - 1)
+        .max(start);
+    Some(start..end)
+}
+
+/// Parse a `START:END` line range, or a single line number
+fn parse_line_range(value: &str) -> Result<(usize, usize), String> {
+    let (first, last) = value.split_once(':').unwrap_or((value, value));
+    let parse = |n: &str| {
+        n.trim()
+            .parse::<usize>()
+            .map_err(|_| format!("invalid line number `{}`", n))
+    };
+    let (first, last) = (parse(first)?, parse(last)?);
+    if first == 0 || last < first {
+        return Err(format!(
+            "invalid line range `{}`; expected START:END with 1 <= START <= END",
+            value
+        ));
+    }
+    Ok((first, last))
+}
+
+/// Unified diff from `original` to `formatted`, coloured when stdout is a terminal
+fn render_diff(name: &str, original: &str, formatted: &str) -> String {
+    let diff = TextDiff::from_lines(original, formatted)
+        .unified_diff()
+        .header(name, name)
+        .to_string();
+    if !io::stdout().is_terminal() {
+        return diff;
+    }
+
+    let mut colored = String::with_capacity(diff.len());
+    for line in diff.split_inclusive('\n') {
+        let color = if line.starts_with("---") || line.starts_with("+++") {
+            "\x1b[1m"
+        } else if line.starts_with("@@") {
+            "\x1b[36m"
+        } else if line.starts_with('-') {
+            "\x1b[31m"
+        } else if line.starts_with('+') {
+            "\x1b[32m"
+        } else {
+            colored.push_str(line);
+            continue;
+        };
+        let (text, newline) = match line.strip_suffix('\n') {
+            Some(text) => (text, "\n"),
+            None => (line, ""),
+        };
+        colored.push_str(color);
+        colored.push_str(text);
+        colored.push_str("\x1b[0m");
+        colored.push_str(newline);
+    }
+    colored
+}
@@ -21,6 +21,7 @@
 //! println!("Parsed {} node with {} children", root.kind(), root.child_count());
 //! ```
 
+use crate::error::FormatError;
 use once_cell::sync::Lazy;
 use tree_sitter::{Language, Parser, Tree};
 use tree_sitter_pony::language as pony_language;
@@ -42,10 +43,9 @@ pub static PONY_LANGUAGE: Lazy<Language> = Lazy::new(pony_language);
 ///
 /// # Returns
 ///
-/// Returns a `Result` containing the parsed `Tree` on success, or an error if
-/// parsing fails. Common causes of failure include:
-/// - Invalid or severely malformed syntax
-/// - Internal tree-sitter parsing errors
+/// Returns the parsed `Tree` on success. Fails with
+/// [`FormatError::Language`] if the grammar cannot be loaded, or
+/// [`FormatError::Parse`] if tree-sitter gives up on the source.
 ///
 /// # Example
 ///
@@ -64,10 +64,10 @@ pub static PONY_LANGUAGE: Lazy<Language> = Lazy::new(pony_language);
 /// The parser is somewhat tolerant of syntax errors and will attempt to produce
 /// a partial AST even for malformed input. Check the tree for error nodes if
 /// you need to validate syntax correctness.
-pub fn parse(source: &str) -> anyhow::Result<Tree> {
+pub fn parse(source: &str) -> Result<Tree, FormatError> {
     let mut parser = Parser::new();
     parser.set_language(*PONY_LANGUAGE)?;
     parser
         .parse(source, None)
-        .ok_or_else(|| anyhow::anyhow!("Failed to parse Pony source"))
+        .ok_or(FormatError::Parse { path: None })
 }
@@ -0,0 +1,305 @@
+//! Comment attachment
+//!
+//! Comments are `extras` in the tree-sitter grammar: they may show up as a
+//! child of almost any node, and often not of the node they belong to (a
+//! comment trailing the last statement of a method is typically a child of
+//! `source_file`). The formatter therefore does not rely on where comments
+//! sit in the tree. Instead all comments are collected up front, classified by
+//! their position on the source line, and emitted by byte position as the
+//! formatter walks the tree:
+//!
+//! - *leading* comments are written before the first node that starts after
+//!   them, on their own line ([`Placement::OwnLine`]) or inline
+//!   ([`Placement::Inline`]);
+//! - *trailing* comments ([`Placement::EndOfLine`]) are deferred to the end
+//!   of the output line holding the code they followed;
+//! - *dangling* comments that no node claimed are flushed at the end of the
+//!   file.
+//!
+//! Every comment is emitted exactly once: nodes copied verbatim mark the
+//! comments inside them as consumed, and whatever is left at the end of the
+//! file is written out there.
+//!
+//! Comments are also where [`Directive`]s live: `// ponyfmt: off` and
+//! `// ponyfmt: on` bracket code that is kept as written, and
+//! `// ponyfmt: skip` keeps the next declaration, member or statement as
+//! written.
+
+use tree_sitter::Node;
+
+/// Where a comment sits relative to the code on its source line
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Placement {
+    /// Nothing but whitespace before it on its line; it gets a line of its own
+    OwnLine,
+    /// Code before it on the same line and nothing after it; it stays at the
+    /// end of the line holding that code
+    EndOfLine,
+    /// A block comment followed by code on the same line; it stays inline
+    Inline,
+}
+
+/// A formatting directive given by a `// ponyfmt: ...` comment
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Directive {
+    /// `// ponyfmt: off`: keep the code after it as written
+    Off,
+    /// `// ponyfmt: on`: format the code after it again
+    On,
+    /// `// ponyfmt: skip`: keep the next declaration, member or statement as
+    /// written
+    Skip,
+}
+
+impl Directive {
+    /// The directive a comment gives, if any
+    fn parse(comment: &str) -> Option<Self> {
+        let text = comment.strip_prefix("//")?.trim();
+        match text.strip_prefix("ponyfmt:")?.trim() {
+            "off" => Some(Directive::Off),
+            "on" => Some(Directive::On),
+            "skip" => Some(Directive::Skip),
+            _ => None,
+        }
+    }
+}
+
+/// A comment of the source file
+#[derive(Clone, Debug)]
+pub struct Comment {
+    pub start: usize,
+    pub end: usize,
+    /// Start of the whitespace and punctuation directly before the comment on
+    /// its line; a trailing comment belongs to the code ending there
+    pub anchor: usize,
+    pub text: String,
+    pub placement: Placement,
+    pub directive: Option<Directive>,
+}
+
+/// All comments of a file, with a record of which have been emitted
+///
+/// Comments are sorted by position and do not overlap, so lookups are binary
+/// searches; they run for every node the formatter visits.
+#[derive(Debug, Default)]
+pub struct Trivia {
+    comments: Vec<Comment>,
+    emitted: Vec<bool>,
+    /// Index of the first comment not emitted yet
+    pending: usize,
+    /// Indices of the `off` and `on` directives
+    switches: Vec<usize>,
+    /// Indices of the `skip` directives
+    skips: Vec<usize>,
+}
+
+impl Trivia {
+    /// Collect every comment below `root`
+    pub fn collect(root: Node, source: &[u8]) -> Self {
+        let mut comments = Vec::new();
+        collect_comments(root, source, &mut comments);
+        comments.sort_by_key(|c| c.start);
+        let emitted = vec![false; comments.len()];
+        let with_directive = |wanted: fn(Directive) -> bool| {
+            (0..comments.len())
+                .filter(|&i| comments[i].directive.is_some_and(wanted))
+                .collect()
+        };
+        let switches = with_directive(|d| d != Directive::Skip);
+        let skips = with_directive(|d| d == Directive::Skip);
+        Self {
+            comments,
+            emitted,
+            pending: 0,
+            switches,
+            skips,
+        }
+    }
+
+    /// Take the comments ending at or before `pos` that were not emitted yet
+    pub fn take_before(&mut self, pos: usize) -> Vec<Comment> {
+        let mut taken = Vec::new();
+        while let Some(comment) = self.comments.get(self.pending) {
+            if !self.emitted[self.pending] {
+                if comment.end > pos {
+                    break;
+                }
+                self.emitted[self.pending] = true;
+                taken.push(comment.clone());
+            }
+            self.pending += 1;
+        }
+        taken
+    }
+
+    /// Take the end-of-line comments that directly follow code ending at `pos`
+    pub fn take_trailing(&mut self, pos: usize) -> Vec<Comment> {
+        let mut taken = Vec::new();
+        for i in self.first_starting_at(pos)..self.comments.len() {
+            if self.emitted[i] {
+                continue;
+            }
+            let comment = &self.comments[i];
+            if comment.anchor > pos || comment.placement != Placement::EndOfLine {
+                break;
+            }
+            self.emitted[i] = true;
+            taken.push(comment.clone());
+        }
+        taken
+    }
+
+    /// Mark the comments inside `start..end` as emitted because that source
+    /// range was copied to the output verbatim
+    pub fn consume_range(&mut self, start: usize, end: usize) {
+        for i in self.first_starting_at(start)..self.comments.len() {
+            if self.comments[i].end > end {
+                break;
+            }
+            self.emitted[i] = true;
+        }
+    }
+
+    /// Whether the code at `pos` is in a `// ponyfmt: off` region
+    pub fn is_off(&self, pos: usize) -> bool {
+        let before = self
+            .switches
+            .partition_point(|&i| self.comments[i].start <= pos);
+        before > 0 && self.comments[self.switches[before - 1]].directive == Some(Directive::Off)
+    }
+
+    /// Whether a `// ponyfmt: skip` comment lies between `start` and `end`
+    pub fn is_skipped(&self, start: usize, end: usize) -> bool {
+        let first = self
+            .skips
+            .partition_point(|&i| self.comments[i].start < start);
+        self.skips
+            .get(first)
+            .is_some_and(|&i| self.comments[i].end <= end)
+    }
+
+    /// Index of the first comment starting at or after `pos`
+    fn first_starting_at(&self, pos: usize) -> usize {
+        self.comments.partition_point(|c| c.start < pos)
+    }
+
+    /// Whether the comment starting at `start` has already been emitted
+    pub fn is_emitted(&self, start: usize) -> bool {
+        self.comments
+            .binary_search_by_key(&start, |c| c.start)
+            .is_ok_and(|i| self.emitted[i])
+    }
+}
+
+fn collect_comments(node: Node, source: &[u8], out: &mut Vec<Comment>) {
+    if matches!(node.kind(), "line_comment" | "block_comment") {
+        let start = node.start_byte();
+        let end = node.end_byte();
+        let line_start = source[..start]
+            .iter()
+            .rposition(|&b| b == b'\n')
+            .map_or(0, |i| i + 1);
+        let line_end = source[end..]
+            .iter()
+            .position(|&b| b == b'\n')
+            .map_or(source.len(), |i| end + i);
+        let code_before = source[line_start..start]
+            .iter()
+            .any(|b| !b.is_ascii_whitespace());
+        let anchor = source[line_start..start]
+            .iter()
+            .rposition(|b| !b" \t,;([{=>|".contains(b))
+            .map_or(line_start, |i| line_start + i + 1);
+        let code_after = source[end..line_end]
+            .iter()
+            .any(|b| !b.is_ascii_whitespace());
+        let placement = if node.kind() == "block_comment" && code_after {
+            Placement::Inline
+        } else if code_before {
+            Placement::EndOfLine
+        } else {
+            Placement::OwnLine
+        };
+        // The grammar may include the whitespace before a block comment in it
+        let text = String::from_utf8_lossy(&source[start..end])
+            .trim()
+            .to_string();
+        out.push(Comment {
+            start,
+            end,
+            anchor,
+            directive: Directive::parse(&text),
+            text,
+            placement,
+        });
+        return;
+    }
+    for child in node.children(&mut node.walk()) {
+        collect_comments(child, source, out);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parser::parse;
+
+    fn placements(source: &str) -> Vec<(String, Placement)> {
+        let tree = parse(source).unwrap();
+        let trivia = Trivia::collect(tree.root_node(), source.as_bytes());
+        trivia
+            .comments
+            .into_iter()
+            .map(|c| (c.text, c.placement))
+            .collect()
+    }
+
+    #[test]
+    fn comments_are_classified_by_their_line() {
+        let source = "// own\nactor Main // end\n  new create(env: Env) =>\n    /* in */ f()\n";
+        assert_eq!(
+            placements(source),
+            vec![
+                ("// own".to_string(), Placement::OwnLine),
+                ("// end".to_string(), Placement::EndOfLine),
+                ("/* in */".to_string(), Placement::Inline),
+            ]
+        );
+    }
+
+    #[test]
+    fn directives_mark_regions_and_skipped_nodes() {
+        let source = "// ponyfmt: off\nprimitive A\n// ponyfmt: on\nprimitive B\n// ponyfmt: skip\nprimitive C\n";
+        let tree = parse(source).unwrap();
+        let trivia = Trivia::collect(tree.root_node(), source.as_bytes());
+        let at = |name: &str| source.find(name).unwrap();
+        assert!(trivia.is_off(at("primitive A")));
+        assert!(!trivia.is_off(at("primitive B")));
+        assert!(!trivia.is_skipped(at("primitive A"), at("primitive B")));
+        assert!(trivia.is_skipped(at("primitive B"), at("primitive C")));
+    }
+
+    #[test]
+    fn take_before_passes_over_consumed_comments() {
+        let source = "// a\nprimitive A\n// b\nprimitive B\n// c\nprimitive C\n";
+        let tree = parse(source).unwrap();
+        let mut trivia = Trivia::collect(tree.root_node(), source.as_bytes());
+        let at = |name: &str| source.find(name).unwrap();
+        trivia.consume_range(at("// b"), at("primitive B"));
+        let taken = trivia.take_before(at("primitive C"));
+        let texts: Vec<&str> = taken.iter().map(|c| c.text.as_str()).collect();
+        assert_eq!(texts, ["// a", "// c"]);
+        assert!(trivia.take_before(source.len()).is_empty());
+    }
+
+    #[test]
+    fn trailing_comment_anchors_after_separators() {
+        let source = "actor Main\n  new create(env: Env) =>\n    f(a, // one\n      b)\n";
+        let tree = parse(source).unwrap();
+        let mut trivia = Trivia::collect(tree.root_node(), source.as_bytes());
+        let after_a = source.find("a,").unwrap() + 1;
+        let taken = trivia.take_trailing(after_a);
+        assert_eq!(taken.len(), 1);
+        assert!(trivia.is_emitted(taken[0].start));
+    }
+}
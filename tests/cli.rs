@@ -0,0 +1,258 @@
+use std::io::Write;
+use std::process::{Command, Output, Stdio};
+
+/// Run the ponyfmt binary with `args`, feeding `stdin` to it
+fn ponyfmt(args: &[&str], stdin: &str) -> Output {
+    let mut child = Command::new(env!("CARGO_BIN_EXE_ponyfmt"))
+        .args(args)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .expect("failed to run ponyfmt");
+    child
+        .stdin
+        .take()
+        .expect("stdin is piped")
+        .write_all(stdin.as_bytes())
+        .expect("failed to write stdin");
+    child
+        .wait_with_output()
+        .expect("failed to wait for ponyfmt")
+}
+
+#[test]
+fn stdin_prints_only_the_formatted_code() {
+    let output = ponyfmt(
+        &["fmt", "-"],
+        "actor   Main\nnew create(env: Env) =>\nenv.out.print(\"hi\")\n",
+    );
+    assert!(output.status.success());
+    assert_eq!(
+        String::from_utf8_lossy(&output.stdout),
+        "actor Main\n  new create(env: Env) =>\n    env.out.print(\"hi\")\n"
+    );
+}
+
+#[test]
+fn stdin_filepath_is_used_for_config_discovery() {
+    let dir = std::env::temp_dir().join(format!("ponyfmt-cli-stdin-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    std::fs::write(dir.join("ponyfmt.toml"), "indent_width = 4\n").unwrap();
+    let filepath = dir.join("main.pony");
+
+    let output = ponyfmt(
+        &[
+            "fmt",
+            "--stdin",
+            "--stdin-filepath",
+            filepath.to_str().unwrap(),
+        ],
+        "actor Main\n  new create(env: Env) =>\n    None\n",
+    );
+    std::fs::remove_dir_all(&dir).unwrap();
+    assert!(output.status.success());
+    assert_eq!(
+        String::from_utf8_lossy(&output.stdout),
+        "actor Main\n    new create(env: Env) =>\n        None\n"
+    );
+}
+
+#[test]
+fn stdin_filepath_requires_formatting_stdin() {
+    let output = ponyfmt(&["fmt", "--stdin-filepath", "main.pony"], "actor Main\n");
+    assert!(!output.status.success());
+    assert!(String::from_utf8_lossy(&output.stderr).contains("--stdin-filepath requires"));
+}
+
+#[test]
+fn write_sorts_use_statements_when_configured() {
+    let dir = std::env::temp_dir().join(format!("ponyfmt-cli-sort-uses-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    std::fs::write(dir.join("ponyfmt.toml"), "sort_uses = true\n").unwrap();
+    let file = dir.join("main.pony");
+    std::fs::write(
+        &file,
+        "use \"time\"\nuse \"collections\"\n\nactor Main\n  new create(env: Env) =>\n    None\n",
+    )
+    .unwrap();
+
+    let output = ponyfmt(&["fmt", "--write", file.to_str().unwrap()], "");
+    let written = std::fs::read_to_string(&file).unwrap();
+    std::fs::remove_dir_all(&dir).unwrap();
+    assert!(
+        output.status.success(),
+        "{}",
+        String::from_utf8_lossy(&output.stderr)
+    );
+    assert_eq!(
+        written,
+        "use \"collections\"\nuse \"time\"\n\nactor Main\n  new create(env: Env) =>\n    None\n"
+    );
+}
+
+#[test]
+fn diff_mode_prints_a_unified_diff_and_fails() {
+    let output = ponyfmt(
+        &["fmt", "--diff", "--stdin-filepath", "main.pony", "-"],
+        "actor Main\nnew create(env: Env) =>\n  None\n",
+    );
+    assert_eq!(output.status.code(), Some(1));
+    assert_eq!(
+        String::from_utf8_lossy(&output.stdout),
+        "--- main.pony\n+++ main.pony\n@@ -1,3 +1,3 @@\n actor Main\n-new create(env: Env) =>\n-  None\n+  new create(env: Env) =>\n+    None\n"
+    );
+}
+
+#[test]
+fn lines_limits_formatting_to_the_given_lines() {
+    let output = ponyfmt(
+        &["fmt", "--lines", "3:3", "-"],
+        "actor Main\n  new create(env: Env) =>\n    foo( 1,2 )\n    bar( 3 )\n",
+    );
+    assert!(output.status.success());
+    assert_eq!(
+        String::from_utf8_lossy(&output.stdout),
+        "actor Main\n  new create(env: Env) =>\n    foo(1, 2)\n    bar( 3 )\n"
+    );
+}
+
+#[test]
+fn lines_past_the_end_of_the_file_format_nothing() {
+    let input = "actor Main\n  new create(env: Env) =>\n    foo( 1,2 )\n";
+    let output = ponyfmt(&["fmt", "--lines", "10:12", "-"], input);
+    assert!(output.status.success());
+    assert_eq!(String::from_utf8_lossy(&output.stdout), input);
+}
+
+#[test]
+fn syntax_errors_are_reported_with_a_snippet() {
+    let input = "actor Main\n  fun f() =>\n    foo(1,, 2)\n";
+    let output = ponyfmt(&["fmt", "--stdin-filepath", "main.pony", "-"], input);
+    assert_eq!(output.status.code(), Some(1));
+    assert!(output.stdout.is_empty());
+    assert!(String::from_utf8_lossy(&output.stderr).starts_with(
+        "error: unexpected `,`\n --> main.pony:3:10\n  |\n3 |     foo(1,, 2)\n  |          ^ unexpected token\n"
+    ));
+
+    let output = ponyfmt(&["fmt", "--allow-errors", "-"], input);
+    assert!(output.status.success());
+}
+
+/// Frame JSON-RPC messages the way an LSP client sends them
+fn lsp_messages(messages: &[&str]) -> String {
+    messages
+        .iter()
+        .map(|m| format!("Content-Length: {}\r\n\r\n{}", m.len(), m))
+        .collect()
+}
+
+#[test]
+fn lsp_answers_formatting_requests() {
+    let input = lsp_messages(&[
+        r#"{"jsonrpc":"2.0","id":1,"method":"initialize","params":{}}"#,
+        r#"{"jsonrpc":"2.0","method":"initialized","params":{}}"#,
+        r#"{"jsonrpc":"2.0","method":"textDocument/didOpen","params":{"textDocument":{"uri":"untitled:a.pony","languageId":"pony","version":1,"text":"actor Main\n  fun  f(): U32 => 1\n"}}}"#,
+        r#"{"jsonrpc":"2.0","id":2,"method":"textDocument/formatting","params":{"textDocument":{"uri":"untitled:a.pony"},"options":{"tabSize":2,"insertSpaces":true}}}"#,
+        r#"{"jsonrpc":"2.0","id":3,"method":"shutdown"}"#,
+        r#"{"jsonrpc":"2.0","method":"exit"}"#,
+    ]);
+    let output = ponyfmt(&["lsp"], &input);
+    assert!(output.status.success());
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert!(stdout.contains(r#""documentFormattingProvider":true"#));
+    assert!(stdout.contains(
+        r#""result":[{"newText":"","range":{"end":{"character":7,"line":1},"start":{"character":6,"line":1}}}]"#
+    ));
+    assert!(stdout.contains(r#"{"id":3,"jsonrpc":"2.0","result":null}"#));
+}
+
+#[test]
+fn lsp_answers_range_and_on_type_formatting_requests() {
+    let input = lsp_messages(&[
+        r#"{"jsonrpc":"2.0","id":1,"method":"initialize","params":{}}"#,
+        r#"{"jsonrpc":"2.0","method":"textDocument/didOpen","params":{"textDocument":{"uri":"untitled:a.pony","languageId":"pony","version":1,"text":"actor Main\n  new create(env: Env) =>\n    foo( 1,2 )\n    bar( 3 )\n"}}}"#,
+        r#"{"jsonrpc":"2.0","id":2,"method":"textDocument/rangeFormatting","params":{"textDocument":{"uri":"untitled:a.pony"},"range":{"start":{"line":2,"character":0},"end":{"line":2,"character":14}},"options":{"tabSize":2,"insertSpaces":true}}}"#,
+        r#"{"jsonrpc":"2.0","id":3,"method":"textDocument/onTypeFormatting","params":{"textDocument":{"uri":"untitled:a.pony"},"position":{"line":3,"character":0},"ch":"\n","options":{"tabSize":2,"insertSpaces":true}}}"#,
+        r#"{"jsonrpc":"2.0","id":4,"method":"textDocument/onTypeFormatting","params":{"textDocument":{"uri":"untitled:a.pony"},"position":{"line":9,"character":0},"ch":"\n","options":{"tabSize":2,"insertSpaces":true}}}"#,
+        r#"{"jsonrpc":"2.0","method":"textDocument/didChange","params":{"textDocument":{"uri":"untitled:a.pony","version":2},"contentChanges":[{"text":"actor Main\n  new create(env: Env) =>\n    foo(1,, 2)\n    bar( 3 )\n"}]}}"#,
+        r#"{"jsonrpc":"2.0","id":5,"method":"textDocument/rangeFormatting","params":{"textDocument":{"uri":"untitled:a.pony"},"range":{"start":{"line":3,"character":0},"end":{"line":3,"character":12}},"options":{"tabSize":2,"insertSpaces":true}}}"#,
+        r#"{"jsonrpc":"2.0","id":6,"method":"textDocument/onTypeFormatting","params":{"textDocument":{"uri":"untitled:a.pony"},"position":{"line":4,"character":0},"ch":"\n","options":{"tabSize":2,"insertSpaces":true}}}"#,
+        r#"{"jsonrpc":"2.0","id":7,"method":"shutdown"}"#,
+        r#"{"jsonrpc":"2.0","method":"exit"}"#,
+    ]);
+    let output = ponyfmt(&["lsp"], &input);
+    assert!(output.status.success());
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    let edit = r#""result":[{"newText":"1, 2","range":{"end":{"character":13,"line":2},"start":{"character":8,"line":2}}}]"#;
+    assert!(stdout.contains(&format!(r#"{{"id":2,"jsonrpc":"2.0",{}}}"#, edit)));
+    assert!(stdout.contains(&format!(r#"{{"id":3,"jsonrpc":"2.0",{}}}"#, edit)));
+    // Past the end of the document and while it has syntax errors: no edits
+    for id in [4, 5, 6] {
+        assert!(
+            stdout.contains(&format!(r#"{{"id":{},"jsonrpc":"2.0","result":[]}}"#, id)),
+            "{}",
+            stdout
+        );
+    }
+}
+
+/// Run git with `args` in `dir`, failing the test if it fails
+fn git(dir: &std::path::Path, args: &[&str]) {
+    let status = Command::new("git")
+        .args([
+            "-c",
+            "user.name=ponyfmt",
+            "-c",
+            "user.email=ponyfmt@example.com",
+        ])
+        .args(args)
+        .current_dir(dir)
+        .stdout(Stdio::null())
+        .status()
+        .expect("failed to run git");
+    assert!(status.success(), "git {:?} failed", args);
+}
+
+#[test]
+fn changed_since_formats_changed_lines_and_untracked_files() {
+    let dir = std::env::temp_dir().join(format!("ponyfmt-cli-git-{}", std::process::id()));
+    let _ = std::fs::remove_dir_all(&dir);
+    std::fs::create_dir_all(&dir).unwrap();
+    git(&dir, &["init", "-q"]);
+    // Paths in the diff must not depend on the user's prefix settings
+    git(&dir, &["config", "diff.mnemonicPrefix", "true"]);
+    std::fs::write(
+        dir.join("main.pony"),
+        "actor Main\n  new create(env: Env) =>\n    foo( 1 )\n    bar( 2 )\n",
+    )
+    .unwrap();
+    git(&dir, &["add", "main.pony"]);
+    git(&dir, &["commit", "-q", "-m", "initial"]);
+
+    std::fs::write(
+        dir.join("main.pony"),
+        "actor Main\n  new create(env: Env) =>\n    foo( 1 )\n    bar( 3 )\n",
+    )
+    .unwrap();
+    std::fs::write(dir.join("new.pony"), "actor   New\n").unwrap();
+
+    let output = Command::new(env!("CARGO_BIN_EXE_ponyfmt"))
+        .args(["fmt", "--write", "--changed-since", "HEAD"])
+        .current_dir(&dir)
+        .output()
+        .expect("failed to run ponyfmt");
+    let main = std::fs::read_to_string(dir.join("main.pony")).unwrap();
+    let new = std::fs::read_to_string(dir.join("new.pony")).unwrap();
+    std::fs::remove_dir_all(&dir).unwrap();
+    assert!(
+        output.status.success(),
+        "{}",
+        String::from_utf8_lossy(&output.stderr)
+    );
+    assert_eq!(
+        main,
+        "actor Main\n  new create(env: Env) =>\n    foo( 1 )\n    bar(3)\n"
+    );
+    assert_eq!(new, "actor New\n");
+}
@@ -0,0 +1,162 @@
+use ponyfmt::equivalence::{EquivalenceError, check_equivalence};
+use ponyfmt::error::{FormatError, Span};
+use std::path::Path;
+
+/// The equivalence error inside a formatter error
+fn equivalence_error(err: &FormatError) -> Option<&EquivalenceError> {
+    match err {
+        FormatError::NotEquivalent { error, .. } => Some(error),
+        _ => None,
+    }
+}
+use ponyfmt::formatter::{FormatOptions, format_source};
+
+fn fmt(src: &str) -> String {
+    format_source(src, &FormatOptions::default()).unwrap()
+}
+
+#[test]
+fn formatting_keeps_nested_calls_and_commas_in_strings() {
+    let input = r#"actor Main
+new create(env: Env) =>
+env.out.print(f(g(a, b), "x, y"), c)
+"#;
+    let formatted = fmt(input);
+    assert!(formatted.contains(r#"env.out.print(f(g(a, b), "x, y"), c)"#));
+    check_equivalence(input, &formatted).unwrap();
+}
+
+#[test]
+fn docstrings_may_be_reindented() {
+    let original = "class A\n      \"\"\"\n      Doc\n        text\n      \"\"\"\n";
+    let formatted = "class A\n  \"\"\"\n  Doc\n    text\n  \"\"\"\n";
+    check_equivalence(original, formatted).unwrap();
+
+    let changed = "class A\n  \"\"\"\n  Doc\n    test\n  \"\"\"\n";
+    assert!(check_equivalence(original, changed).is_err());
+}
+
+#[test]
+fn whitespace_of_a_string_method_result_is_compared() {
+    let original =
+        "actor Main\n  fun text(): String =>\n    \"\"\"\n      indented\n\n    \"\"\"\n";
+    let reindented = "actor Main\n  fun text(): String =>\n    \"\"\"\n    indented\n    \"\"\"\n";
+    assert!(check_equivalence(original, reindented).is_err());
+}
+
+#[test]
+fn changed_token_is_reported_with_its_position() {
+    let original = "actor Main\n  new create(env: Env) =>\n    f(\"a, b\")\n";
+    let mangled = "actor Main\n  new create(env: Env) =>\n    f(\"a\", \"b\")\n";
+    let err = check_equivalence(original, mangled).unwrap_err();
+    assert_eq!(err.span(), Some(Span::at(3, 8, 4)));
+    match equivalence_error(&err) {
+        Some(EquivalenceError::Changed {
+            original,
+            formatted,
+        }) => {
+            assert_eq!(original.text, "a, b");
+            assert_eq!((original.line, original.column), (3, 8));
+            assert_eq!(formatted.text, "a");
+        }
+        other => panic!("unexpected result: {:?}", other),
+    }
+}
+
+#[test]
+fn new_syntax_errors_are_rejected() {
+    let original = "actor Main\n  new create(env: Env) =>\n    f(a)\n";
+    let broken = "actor Main\n  new create(env: Env) =>\n    f(a\n";
+    let err = check_equivalence(original, broken).unwrap_err();
+    assert!(matches!(
+        equivalence_error(&err),
+        Some(EquivalenceError::NewSyntaxError { .. })
+    ));
+    // The position is in the output, so it is only named in the message
+    assert_eq!(err.span(), None);
+}
+
+#[test]
+fn whitespace_and_comment_positions_are_ignored() {
+    let original = "actor Main\nnew create(env: Env) =>\n// hi\nf(a,b)";
+    let formatted = "actor Main\n  new create(env: Env) => // hi\n    f(a, b)\n";
+    check_equivalence(original, formatted).unwrap();
+}
+
+#[test]
+fn tokens_inside_syntax_errors_are_compared() {
+    let original = "actor Main\n  new create(env: Env) =>\n    foo(1,, 2)\n";
+    let formatted = "actor Main\n  new create(env: Env) =>\n    foo(1, 2)\n";
+    let err = check_equivalence(original, formatted).unwrap_err();
+    assert!(matches!(
+        equivalence_error(&err),
+        Some(EquivalenceError::Changed { .. })
+    ));
+}
+
+#[test]
+fn lost_comments_are_reported() {
+    let original = "actor Main\n  new create(env: Env) =>\n    f(a) // keep me\n";
+    let formatted = "actor Main\n  new create(env: Env) =>\n    f(a)\n";
+    let err = check_equivalence(original, formatted).unwrap_err();
+    match equivalence_error(&err) {
+        Some(EquivalenceError::CommentLost { original }) => {
+            assert_eq!(original.text, "// keep me");
+            assert_eq!(original.line, 3);
+        }
+        other => panic!("unexpected result: {:?}", other),
+    }
+}
+
+#[test]
+fn errors_are_displayed_with_path_and_position() {
+    let original = "actor Main\n  new create(env: Env) =>\n    f(a) // keep me\n";
+    let formatted = "actor Main\n  new create(env: Env) =>\n    f(a)\n";
+    let err = check_equivalence(original, formatted)
+        .unwrap_err()
+        .with_path(Path::new("src/main.pony"));
+    assert_eq!(
+        err.to_string(),
+        "src/main.pony:3:10: formatting would lose the comment `// keep me` (line_comment) at 3:10"
+    );
+}
+
+#[test]
+fn runs_of_use_statements_may_be_reordered() {
+    let original = "use \"time\"\nuse \"collections\"\n\nactor Main\n";
+    let sorted = "use \"collections\"\nuse \"time\"\n\nactor Main\n";
+    check_equivalence(original, sorted).unwrap();
+
+    let replaced = "use \"collections\"\nuse \"files\"\n\nactor Main\n";
+    assert!(check_equivalence(original, replaced).is_err());
+}
+
+#[test]
+fn whitespace_in_string_literals_is_compared() {
+    let original =
+        "actor Main\n  fun f(): String =>\n    \"\"\"\n    Doc\n    \"\"\"\n    \"\"\"a  b\"\"\"\n";
+    let docstring = "actor Main\n  fun f(): String =>\n    \"\"\"Doc\"\"\"\n    \"\"\"a  b\"\"\"\n";
+    check_equivalence(original, docstring).unwrap();
+
+    let literal =
+        "actor Main\n  fun f(): String =>\n    \"\"\"\n    Doc\n    \"\"\"\n    \"\"\"a b\"\"\"\n";
+    assert!(check_equivalence(original, literal).is_err());
+}
+
+#[test]
+fn expressions_joined_onto_one_line_are_rejected() {
+    let original = "actor Main\n  new create(env: Env) =>\n    let f = {(x: U32): U32 =>\n      let y = x + 1\n      y * 2\n    }\n";
+    let joined = "actor Main\n  new create(env: Env) =>\n    let f = {(x: U32): U32 => let y = x + 1y * 2}\n";
+    let err = check_equivalence(original, joined).unwrap_err();
+    match equivalence_error(&err) {
+        Some(EquivalenceError::LineChanged {
+            original,
+            formatted,
+        }) => {
+            assert_eq!(original.text, "y");
+            assert_eq!((original.line, original.column), (5, 7));
+            assert_eq!((formatted.line, formatted.column), (3, 44));
+        }
+        other => panic!("unexpected result: {:?}", other),
+    }
+}
@@ -1,4 +1,6 @@
-use ponyfmt::formatter::{FormatOptions, Mode, format_source};
+use ponyfmt::equivalence::check_equivalence;
+use ponyfmt::error::{FormatError, Span};
+use ponyfmt::formatter::{FormatOptions, Mode, format_range, format_source};
 use std::fs;
 use std::path::{Path, PathBuf};
 use walkdir::WalkDir;
@@ -193,16 +195,619 @@ env.out.print("Hi")
 
 #[test]
 fn conditional_formatting() {
+    // A top-level `if` is a syntax error, so it is kept as written
     let input = r#"if true then
 env.out.print("yes")
 end"#;
     let expected = r#"if true then
-  env.out.print("yes")
+env.out.print("yes")
 end
+"#;
+    let opts = FormatOptions {
+        allow_errors: true,
+        ..FormatOptions::default()
+    };
+    assert_eq!(format_source(input, &opts).unwrap(), expected);
+}
+
+#[test]
+fn match_cases_align_with_match() {
+    let input = r#"actor Main
+  new create(env: Env) =>
+      match   x
+  |   let s: String   if s.size() > 0 =>   env.out.print(s)   // string
+       | None=>None
+    | (let a: U32, 2) =>
+            foo(  a )
+    bar()
+       else
+    baz()
+     end
+"#;
+    let expected = r#"actor Main
+  new create(env: Env) =>
+    match x
+    | let s: String if s.size() > 0 => env.out.print(s) // string
+    | None => None
+    | (let a: U32, 2) =>
+      foo(a)
+      bar()
+    else
+      baz()
+    end
+"#;
+    assert_eq!(fmt(input), expected);
+}
+
+#[test]
+fn long_match_case_bodies_move_below_the_case() {
+    let input = r#"actor Main
+  new create(env: Env) =>
+    let y = match z | 1 => "a" | 2 => EmailMessage.create("alice@example.com", "Welcome aboard to the team, so glad to have you!") else "b" end
+"#;
+    let expected = r#"actor Main
+  new create(env: Env) =>
+    let y = match z
+    | 1 => "a"
+    | 2 =>
+      EmailMessage.create("alice@example.com", "Welcome aboard to the team, so glad to have you!")
+    else
+      "b"
+    end
+"#;
+    assert_eq!(fmt(input), expected);
+}
+
+#[test]
+fn conditionals_indent_every_branch() {
+    let input = r#"actor Main
+  new create(env: Env) =>
+    if a then foo()
+  elseif   b then
+        bar()
+     else baz() end
+    ifdef windows then
+  win()
+    elseif not windows and (linux or osx) then
+  posix()
+    end
+    iftype A <: U8 then  1 elseif A <: U16 then 2 else 3 end
+"#;
+    let expected = r#"actor Main
+  new create(env: Env) =>
+    if a then
+      foo()
+    elseif b then
+      bar()
+    else
+      baz()
+    end
+    ifdef windows then
+      win()
+    elseif not windows and (linux or osx) then
+      posix()
+    end
+    iftype A <: U8 then
+      1
+    elseif A <: U16 then
+      2
+    else
+      3
+    end
+"#;
+    assert_eq!(fmt(input), expected);
+}
+
+#[test]
+fn loops_indent_their_bodies_and_else_clauses() {
+    let input = r#"actor Main
+  new create(env: Env) =>
+    while   i < 10 do
+  foo(  i )
+      else
+ none()
+        end
+    for (k, v) in   map.pairs() do bar(k, v) end
+    repeat
+    i = 1
+      until i == 0 end
+    repeat baz() until done() else
+    none() end
+"#;
+    let expected = r#"actor Main
+  new create(env: Env) =>
+    while i < 10 do
+      foo(i)
+    else
+      none()
+    end
+    for (k, v) in map.pairs() do
+      bar(k, v)
+    end
+    repeat
+      i = 1
+    until i == 0 end
+    repeat
+      baz()
+    until done()
+    else
+      none()
+    end
+"#;
+    assert_eq!(fmt(input), expected);
+}
+
+#[test]
+fn try_blocks_and_partial_calls() {
+    let input = r#"actor Main
+  fun parse(s: String): U32? =>
+    s.u32()?
+
+  new create(env: Env) =>
+    try
+    let x = parse("1")?
+          env.out.print(x.string())
+      else env.out.print("bad")
+     then
+      env.out.print("done") end
+    try foo( )? end
+"#;
+    let expected = r#"actor Main
+  fun parse(s: String): U32 ? => s.u32()?
+
+  new create(env: Env) =>
+    try
+      let x = parse("1")?
+      env.out.print(x.string())
+    else
+      env.out.print("bad")
+    then
+      env.out.print("done")
+    end
+    try
+      foo()?
+    end
+"#;
+    assert_eq!(fmt(input), expected);
+}
+
+#[test]
+fn lambdas_and_object_literals_break_only_when_too_long() {
+    let input = r#"actor Main
+  new create(env: Env) =>
+    let f = {(x:U32):U32=>x + 1}
+    let g = {ref(a: U32, b: U32)(env, x=y): U32 ? => env.out.print("hi"); a + b} iso
+    let callback = {(request: Request val, response: Response iso): Response iso^ => consume response}
+    let o = object is Foo fun apply(): U32 => 1 end
+    let p = object ref is Counter
+    var count: U32 = 0
+    fun ref apply() => count = count + 1
+    end
+"#;
+    let expected = r#"actor Main
+  new create(env: Env) =>
+    let f = {(x: U32): U32 => x + 1}
+    let g = {ref(a: U32, b: U32)(env, x = y): U32 ? => env.out.print("hi"); a + b} iso
+    let callback = {(request: Request val, response: Response iso): Response iso^ =>
+      consume response
+    }
+    let o = object is Foo fun apply(): U32 => 1 end
+    let p = object ref is Counter
+      var count: U32 = 0
+      fun ref apply() => count = count + 1
+    end
+"#;
+    assert_eq!(fmt(input), expected);
+}
+
+#[test]
+fn lambda_bodies_keep_expressions_on_separate_lines() {
+    let input = r#"actor Main
+  new create(env: Env) =>
+    let f = {(x: U32): U32 =>
+      let y = x + 1
+      y * 2
+    }
+    let g = {(a: Bool) => if a then b() end
+      c()}
+"#;
+    let expected = r#"actor Main
+  new create(env: Env) =>
+    let f = {(x: U32): U32 =>
+      let y = x + 1
+      y * 2
+    }
+    let g = {(a: Bool) =>
+      if a then
+        b()
+      end
+      c()
+    }
+"#;
+    let output = fmt(input);
+    assert_eq!(output, expected);
+    check_equivalence(input, &output).unwrap();
+    assert_eq!(fmt(&output), output);
+}
+
+#[test]
+fn consecutive_assignments_stay_on_their_own_lines() {
+    let input =
+        "actor Main\n  new create(env: Env) =>\n    let a = 1\n    let b = 2\n    let c = 3\n";
+    assert_eq!(fmt(input), input);
+}
+
+#[test]
+fn recover_consume_and_with_blocks() {
+    let input = r#"actor Main
+  new create(env: Env) =>
+    let a = recover iso   Array[U8] end
+    let b = recover val
+    let s = String
+          s.append("x")
+      s end
+    send(consume   a)
+    let c = consume  ref b
+    with f = File(p), g = File(q) do f.print("x") end
+"#;
+    let expected = r#"actor Main
+  new create(env: Env) =>
+    let a = recover iso Array[U8] end
+    let b = recover val
+      let s = String
+      s.append("x")
+      s
+    end
+    send(consume a)
+    let c = consume ref b
+    with f = File(p), g = File(q) do
+      f.print("x")
+    end
+"#;
+    assert_eq!(fmt(input), expected);
+}
+
+#[test]
+fn type_parameters_break_one_per_line_when_too_long() {
+    let input = r#"class Foo[A: (Stringable & Any val), B: Any = None]
+  let x: Array[(String, U32)] iso
+  let m: Map[String,U32] = Map[String,U32]()
+  fun get[C: Any val](c: C): Map[String,U32] => m
+
+class Registry[Key: (Hashable & Equatable[Key] val), Value: Any #share, Handler: Notifier[Key, Value] = DefaultNotifier] is Store[Key,Value]
+
+type Pair[X] is (X, X)
+"#;
+    let expected = r#"class Foo[A: (Stringable & Any val), B: Any = None]
+  let x: Array[(String, U32)] iso
+  let m: Map[String, U32] = Map[String, U32]()
+  fun get[C: Any val](c: C): Map[String, U32] => m
+
+class Registry[
+  Key: (Hashable & Equatable[Key] val),
+  Value: Any #share,
+  Handler: Notifier[Key, Value] = DefaultNotifier
+] is Store[Key, Value]
+
+type Pair[X] is (X, X)
+"#;
+    assert_eq!(fmt(input), expected);
+}
+
+#[test]
+fn union_types_break_with_a_leading_bar() {
+    let input = r#"type A is (B|C |D)
+type E is (F&G)
+type T is (U32,String)
+type Msg is (Connect | Disconnect | Publish | Subscribe | Unsubscribe | Ping | Pong | Acknowledge | Error)
+
+class X
+  fun get(): this->Foo => f
+"#;
+    let expected = r#"type A is (B | C | D)
+type E is (F & G)
+type T is (U32, String)
+type Msg is
+  ( Connect
+  | Disconnect
+  | Publish
+  | Subscribe
+  | Unsubscribe
+  | Ping
+  | Pong
+  | Acknowledge
+  | Error
+  )
+
+class X
+  fun get(): this->Foo => f
+"#;
+    assert_eq!(fmt(input), expected);
+}
+
+#[test]
+fn long_method_signatures_break_one_parameter_per_line() {
+    let input = r#"actor Main
+  fun ref f(a:U32,b: String = "x", c: Array[U8] iso) : U32 ? => 1
+
+  new create(env: Env, connection_handler_factory: ConnectionHandlerFactory, timeout: U64 = 5_000) =>
+    None
+
+  fun tag handle(request: Request val, response_builder: ResponseBuilder, retries: USize): Response val ? =>
+    request.build()?
+
+  fun lookup_entry_index(first_parameter: String, second_parameter: U32, third: U8): Map[String, Array[U32]] =>
+    None
+"#;
+    let expected = r#"actor Main
+  fun ref f(a: U32, b: String = "x", c: Array[U8] iso): U32 ? => 1
+
+  new create(
+    env: Env,
+    connection_handler_factory: ConnectionHandlerFactory,
+    timeout: U64 = 5_000
+  ) =>
+    None
+
+  fun tag handle(
+    request: Request val,
+    response_builder: ResponseBuilder,
+    retries: USize
+  ): Response val ? => request.build()?
+
+  fun lookup_entry_index(
+    first_parameter: String,
+    second_parameter: U32,
+    third: U8
+  ): Map[String, Array[U32]] => None
+"#;
+    assert_eq!(fmt(input), expected);
+}
+
+#[test]
+fn docstrings_are_reindented_to_their_declaration() {
+    let input = r#"class Foo
+    """A foo.
+    More text here.
+    """
+  fun get(): U32 =>
+    """Returns x."""
+    x
+
+  fun put(v: U32) =>
+        """
+
+        Sets x.
+        ```pony
+          foo.put(1)
+        ```
+        """
+    None
+
+interface Bar
+  fun apply(): U32
+    """Abstract."""
+"#;
+    let expected = r#"class Foo
+  """
+  A foo.
+  More text here.
+  """
+  fun get(): U32 =>
+    """Returns x."""
+    x
+
+  fun put(v: U32) =>
+    """
+    Sets x.
+    ```pony
+      foo.put(1)
+    ```
+    """
+    None
+
+interface Bar
+  fun apply(): U32
+    """Abstract."""
+"#;
+    assert_eq!(fmt(input), expected);
+}
+
+#[test]
+fn a_string_that_is_the_whole_method_body_is_not_a_docstring() {
+    let input = "actor Main\n  fun text(): String =>\n    \"\"\"\n      indented\n\n    \"\"\"\n";
+    let opts = FormatOptions {
+        verify: true,
+        ..FormatOptions::default()
+    };
+    assert_eq!(format_source(input, &opts).unwrap(), input);
+}
+
+#[test]
+fn docstring_paragraphs_are_wrapped_when_enabled() {
+    let input = r#"primitive Foo
+  """
+  Formats things for the caller. This is a long sentence that needs wrapping.
+
+  - a list item that is long enough to wrap
+  ```pony
+  let x = some_really_long_call(with_args, and_more_args)
+  ```
+  """
+"#;
+    let expected = r#"primitive Foo
+  """
+  Formats things for the caller. This is
+  a long sentence that needs wrapping.
+
+  - a list item that is long enough to
+    wrap
+  ```pony
+  let x = some_really_long_call(with_args, and_more_args)
+  ```
+  """
+"#;
+    let opts = FormatOptions {
+        max_width: 40,
+        wrap_docstrings: true,
+        ..FormatOptions::default()
+    };
+    assert_eq!(format_source(input, &opts).unwrap(), expected);
+}
+
+#[test]
+fn binary_operators_are_spaced_and_long_chains_break_before_operators() {
+    let input = r#"actor Main
+  new create(env: Env) =>
+    i = i - 1
+    let a = (x+y)*z
+    let b = x  and  not y
+    let c = -x +~ (y *~ 2)
+    let d = a - b - c
+    let long_name = first_operand_value + second_operand_value + third_operand_value + fourth_value_x
+"#;
+    let expected = r#"actor Main
+  new create(env: Env) =>
+    i = i - 1
+    let a = (x + y) * z
+    let b = x and not y
+    let c = -x +~ (y *~ 2)
+    let d = a - b - c
+    let long_name = first_operand_value
+      + second_operand_value
+      + third_operand_value
+      + fourth_value_x
+"#;
+    assert_eq!(fmt(input), expected);
+}
+
+#[test]
+fn minus_starting_a_line_is_kept_as_written() {
+    let input = r#"actor Main
+  new create(env: Env) =>
+    foo(
+      a
+      - b
+    )
+"#;
+    assert_eq!(fmt(input), input);
+    assert_eq!(fmt(&input.replace("(\n      a", "(a")), input);
+}
+
+#[test]
+fn statements_sharing_a_line_stay_together() {
+    let input = r#"actor Main
+  fun f(): U32 =>
+    if a then return 1 end
+    while true do break 5 end
+    a;   b
+    return x
+"#;
+    let expected = r#"actor Main
+  fun f(): U32 =>
+    if a then
+      return 1
+    end
+    while true do
+      break 5
+    end
+    a; b
+    return x
+"#;
+    let output = fmt(input);
+    assert_eq!(output, expected);
+    check_equivalence(input, &output).unwrap();
+}
+
+#[test]
+fn format_off_regions_are_kept_as_written() {
+    let input = r#"actor Main
+  new create(env: Env) =>
+    // ponyfmt: off
+    table(  1,  2 )   // aligned
+    table( 10, 20 )   // aligned
+    // ponyfmt: on
+    foo(  3 )
+"#;
+    let expected = r#"actor Main
+  new create(env: Env) =>
+    // ponyfmt: off
+    table(  1,  2 )   // aligned
+    table( 10, 20 )   // aligned
+    // ponyfmt: on
+    foo(3)
+"#;
+    assert_eq!(fmt(input), expected);
+}
+
+#[test]
+fn format_skip_keeps_only_the_next_node() {
+    let input = r#"actor Main
+  // ponyfmt: skip
+  let x:U32   =   0
+  let y:U32   =   0
+
+  new create(env: Env) =>
+    // ponyfmt: skip
+    foo(  1  ,   2 )
+    bar(  1 )
+"#;
+    let expected = r#"actor Main
+  // ponyfmt: skip
+  let x:U32   =   0
+  let y: U32 = 0
+
+  new create(env: Env) =>
+    // ponyfmt: skip
+    foo(  1  ,   2 )
+    bar(1)
 "#;
     assert_eq!(fmt(input), expected);
 }
 
+#[test]
+fn syntax_errors_are_kept_verbatim() {
+    let input = "actor Main\nfun f() =>\n    if x == then\n   y end\nfun  g(): U32 => foo(1,, 2)\n";
+    let expected =
+        "actor Main\n  fun f() =>\n    if x == then\n   y end\n  fun g(): U32 => foo(1,, 2)\n";
+    let opts = FormatOptions {
+        allow_errors: true,
+        ..FormatOptions::default()
+    };
+    assert_eq!(format_source(input, &opts).unwrap(), expected);
+}
+
+#[test]
+fn syntax_errors_keep_their_place_on_the_line() {
+    let opts = FormatOptions {
+        allow_errors: true,
+        verify: true,
+        ..FormatOptions::default()
+    };
+    let unfinished = "actor Main\n  new create(env: Env) =>\n    foo(";
+    assert_eq!(
+        format_source(unfinished, &opts).unwrap(),
+        "actor Main\n  new create(env: Env) =>\n    foo(\n"
+    );
+
+    let header = "class Foo is Stringable $$\n  let _x: U32 = 1\n";
+    assert_eq!(format_source(header, &opts).unwrap(), header);
+}
+
+#[test]
+fn syntax_errors_are_refused_unless_allowed() {
+    let input = "actor Main\n  fun f() =>\n    foo(1,, 2)\n";
+    let err = format_source(input, &FormatOptions::default()).unwrap_err();
+    match err {
+        FormatError::Syntax { diagnostics, .. } => {
+            assert_eq!(diagnostics.len(), 1);
+            assert_eq!(diagnostics[0].message, "unexpected `,`");
+            assert_eq!(diagnostics[0].span, Span::at(3, 10, 1));
+        }
+        other => panic!("expected a syntax error, got {:?}", other),
+    }
+}
+
 #[test]
 fn debug_example_case() {
     let input = r#"actor Main
@@ -217,3 +822,377 @@ end"#;
     println!("Result:\n{}", result);
     // Just test that it runs without panicking for now
 }
+
+#[test]
+fn long_argument_list_breaks_one_per_line() {
+    let input = r#"actor Main
+  new create(env: Env) =>
+    let em = EmailMessage.create(1, "alice@example.com", "Welcome aboard to the team!", "Hello Alice")
+"#;
+    let expected = r#"actor Main
+  new create(env: Env) =>
+    let em = EmailMessage.create(
+      1,
+      "alice@example.com",
+      "Welcome aboard to the team!",
+      "Hello Alice"
+    )
+"#;
+    assert_eq!(fmt(input), expected);
+}
+
+#[test]
+fn named_arguments_follow_the_positional_ones_without_a_comma() {
+    let input = r#"actor Main
+  new create(env: Env) =>
+    let p = Point(1.0, 2.0 where y=3.0)
+    f(where a = 1,b = 2)
+    Rectangle.create(origin, 10 where fill_colour = background_colour, border_colour = outline)
+"#;
+    let expected = r#"actor Main
+  new create(env: Env) =>
+    let p = Point(1.0, 2.0 where y = 3.0)
+    f(where a = 1, b = 2)
+    Rectangle.create(
+      origin,
+      10
+      where fill_colour = background_colour,
+      border_colour = outline
+    )
+"#;
+    let opts = FormatOptions {
+        max_width: 80,
+        verify: true,
+        ..FormatOptions::default()
+    };
+    let output = format_source(input, &opts).unwrap();
+    assert_eq!(output, expected);
+    check_equivalence(input, &output).unwrap();
+}
+
+#[test]
+fn one_line_method_bodies_are_formatted() {
+    let input = r#"actor Main
+  fun f(): U32 => a+b
+  fun g() => foo( 1,2 )
+  fun h(): U32 => some_object.some_method(first_argument_value, second_argument_value, third_value)
+"#;
+    let expected = r#"actor Main
+  fun f(): U32 => a + b
+  fun g() => foo(1, 2)
+  fun h(): U32 =>
+    some_object.some_method(
+      first_argument_value,
+      second_argument_value,
+      third_value
+    )
+"#;
+    let opts = FormatOptions {
+        max_width: 80,
+        verify: true,
+        ..FormatOptions::default()
+    };
+    assert_eq!(format_source(input, &opts).unwrap(), expected);
+}
+
+#[test]
+fn max_width_controls_argument_breaking() {
+    let input = r#"actor Main
+  new create(env: Env) =>
+    env.out.print(em.subject(), g(h(1, 2), "x"))
+"#;
+    let opts = FormatOptions {
+        max_width: 30,
+        ..FormatOptions::default()
+    };
+    let expected = r#"actor Main
+  new create(env: Env) =>
+    env.out.print(
+      em.subject(),
+      g(h(1, 2), "x")
+    )
+"#;
+    assert_eq!(format_source(input, &opts).unwrap(), expected);
+}
+
+#[test]
+fn example_files_are_idempotent() {
+    let opts = FormatOptions {
+        verify: true,
+        ..FormatOptions::default()
+    };
+    for test_case in find_test_cases() {
+        let input = fs::read_to_string(&test_case.input_file).unwrap();
+        if let Err(e) = format_source(&input, &opts) {
+            panic!("{}: {}", test_case.input_file.display(), e);
+        }
+    }
+}
+
+#[test]
+fn verify_accepts_all_member_kinds() {
+    let input = r#"actor Counter
+  var _count: U64 = 0
+  new create() =>
+    None
+  be increment(by: U64) =>
+    _count = _count + by
+  fun ref reset(): U64 =>
+    let old = _count
+    _count = 0
+    old
+
+interface Notify
+  fun apply(x: U32): U32
+
+primitive Math
+  fun add(a: U32, b: U32): U32 => a + b
+"#;
+    let opts = FormatOptions {
+        verify: true,
+        ..FormatOptions::default()
+    };
+    assert_eq!(format_source(input, &opts).unwrap(), input);
+}
+
+#[test]
+fn comments_keep_their_positions() {
+    let input = r#"// leading comment
+actor Main // trailing on header
+  // comment before field
+  let x: U32 = 1 // trailing field
+  new create(env: Env) => // after arrow
+    // inside
+    let y = foo(1, // after one
+      2) // trailing
+    if y then // after then
+      bar()
+    end // after end
+"#;
+    let expected = r#"// leading comment
+actor Main // trailing on header
+  // comment before field
+  let x: U32 = 1 // trailing field
+  new create(env: Env) => // after arrow
+    // inside
+    let y = foo(
+      1, // after one
+      2
+    ) // trailing
+    if y then // after then
+      bar()
+    end // after end
+"#;
+    assert_eq!(fmt(input), expected);
+}
+
+#[test]
+fn inline_block_comments_are_stable() {
+    let input = r#"actor Main
+  fun f(env: Env /* c */, other: U32) =>
+    g(a /* c */, b)
+    None
+"#;
+    let expected = r#"actor Main
+  fun f(env: Env, /* c */ other: U32) =>
+    g(a, /* c */ b)
+    None
+"#;
+    let opts = FormatOptions {
+        verify: true,
+        ..FormatOptions::default()
+    };
+    assert_eq!(format_source(input, &opts).unwrap(), expected);
+}
+
+#[test]
+fn comments_after_parenthesized_types_stay_on_their_line() {
+    let input = "class Foo is (A & B) // c1\n\nprimitive P\n";
+    assert_eq!(fmt(input), input);
+}
+
+#[test]
+fn comments_inside_binary_chains_are_stable() {
+    let input = r#"actor Main
+  fun f(): U32 =>
+    a + // plus
+      b
+  fun g(): U32 =>
+    g(a,
+      b)
+  new create(env: Env) =>
+    let total = first_value + // the first
+      second_value + third_value + fourth_value + fifth_value + sixth_value
+"#;
+    let expected = r#"actor Main
+  fun f(): U32 => a + b // plus
+  fun g(): U32 => g(a, b)
+  new create(env: Env) =>
+    let total = first_value
+      + second_value // the first
+      + third_value
+      + fourth_value
+      + fifth_value
+      + sixth_value
+"#;
+    let opts = FormatOptions {
+        max_width: 60,
+        verify: true,
+        ..FormatOptions::default()
+    };
+    assert_eq!(format_source(input, &opts).unwrap(), expected);
+}
+
+#[test]
+fn every_comment_appears_exactly_once() {
+    let input = r#"actor Main
+  fun foo(a: U32, /* mid */ b: U32): U32 =>
+    a + b // sum
+  // end of members
+// end of file
+"#;
+    let output = fmt(input);
+    for comment in ["/* mid */", "// sum", "// end of members", "// end of file"] {
+        assert_eq!(
+            output.matches(comment).count(),
+            1,
+            "{}\n{}",
+            comment,
+            output
+        );
+    }
+}
+
+#[test]
+fn blank_lines_are_kept_up_to_the_limit() {
+    let input = r#"actor Main
+  let x: U32 = 1
+
+
+
+  let y: U32 = 2
+  new create(env: Env) =>
+    env.out.print("a")
+
+    env.out.print("b")
+"#;
+    let expected = r#"actor Main
+  let x: U32 = 1
+
+  let y: U32 = 2
+  new create(env: Env) =>
+    env.out.print("a")
+
+    env.out.print("b")
+"#;
+    assert_eq!(fmt(input), expected);
+
+    let opts = FormatOptions {
+        max_blank_lines: 0,
+        ..FormatOptions::default()
+    };
+    assert_eq!(
+        format_source(input, &opts).unwrap(),
+        expected.replace("\n\n", "\n")
+    );
+}
+
+#[test]
+fn sort_uses_orders_each_run_of_use_statements() {
+    let input = r#"use "time" // clock
+use "collections"
+// networking
+use "net"
+use "files"
+
+actor Main
+"#;
+    let expected = r#"use "collections"
+use "time" // clock
+// networking
+use "files"
+use "net"
+
+actor Main
+"#;
+    let opts = FormatOptions {
+        sort_uses: true,
+        ..FormatOptions::default()
+    };
+    assert_eq!(format_source(input, &opts).unwrap(), expected);
+    assert_eq!(fmt(input), input);
+}
+
+#[test]
+fn use_statements_keep_aliases_guards_and_ffi_declarations() {
+    let input = r#"use "time"
+use c = "collections"
+use @printf[I32](fmt: Pointer[U8] tag, ...)
+use "lib:foo" if windows
+
+actor Main
+"#;
+    let sorted = r#"use "lib:foo" if windows
+use "time"
+use @printf[I32](fmt: Pointer[U8] tag, ...)
+use c = "collections"
+
+actor Main
+"#;
+    let opts = FormatOptions {
+        sort_uses: true,
+        verify: true,
+        ..FormatOptions::default()
+    };
+    assert_eq!(format_source(input, &opts).unwrap(), sorted);
+    assert_eq!(fmt(input), input);
+}
+
+#[test]
+fn format_range_only_touches_the_covering_statements() {
+    let input = r#"actor   Main
+  new create(env: Env) =>
+    foo( 1,2 )
+    bar(  3 ) // keep
+    baz(  4 )
+  fun   f(): U32 => 1
+"#;
+    let expected = r#"actor   Main
+  new create(env: Env) =>
+    foo( 1,2 )
+    bar(3) // keep
+    baz(  4 )
+  fun   f(): U32 => 1
+"#;
+    let start = input.find("bar").unwrap() + 2;
+    let opts = FormatOptions {
+        verify: true,
+        ..FormatOptions::default()
+    };
+    assert_eq!(format_range(input, start..start, &opts).unwrap(), expected);
+
+    // A trailing comment on the line does not widen the range
+    let line = input.find("    bar").unwrap()..input.find("    baz").unwrap();
+    assert_eq!(format_range(input, line, &opts).unwrap(), expected);
+}
+
+#[test]
+fn format_range_widens_to_whole_members() {
+    let input = r#"actor   Main
+  new create(env: Env) =>
+    foo( 1,2 )
+  fun   f(): U32 => 1
+"#;
+    let expected = r#"actor   Main
+  new create(env: Env) =>
+    foo(1, 2)
+  fun f(): U32 => 1
+"#;
+    let start = input.find("foo").unwrap();
+    let end = input.find("U32").unwrap();
+    let opts = FormatOptions {
+        verify: true,
+        ..FormatOptions::default()
+    };
+    assert_eq!(format_range(input, start..end, &opts).unwrap(), expected);
+}